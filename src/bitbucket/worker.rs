@@ -4,33 +4,44 @@ use atomic_counter::{AtomicCounter, RelaxedCounter};
 use futures::stream::{self, StreamExt};
 #[allow(unused_imports)]
 use futures::SinkExt as _;
-use generic_error::{GenericError, Result};
 use indicatif::ProgressStyle;
 use reqwest::{header::ACCEPT, RequestBuilder};
 use serde::de::DeserializeOwned;
 
 use crate::bitbucket::types::{
-    get_clone_links, PageResponse, ProjDesc, Project, Repo, RepoUrlBuilder, UserResult,
+    get_clone_links, Branch, Label, Labeled, PageResponse, ProjDesc, Project, Repo, RepoUrlBuilder,
+    UserResult,
 };
-use crate::types::BitBucketOpts;
-use crate::util::bail;
+use crate::error::{Error, ErrorKind, Result};
+use crate::types::{BitBucketOpts, InactiveUsersPolicy};
+use std::collections::HashSet;
 use std::time::Duration;
 
 pub type BitbucketResult<T> = std::result::Result<T, BitbucketError>;
 
 pub struct BitbucketError {
-    is_timeout: bool,
+    kind: ErrorKind,
     msg: String,
     cause: String,
 }
 
+impl BitbucketError {
+    fn into_error(self, verbose: bool) -> Error {
+        if verbose {
+            Error::new(self.kind, &format!("{}\nCause: {}", self.msg, self.cause))
+        } else {
+            Error::new(self.kind, &self.msg)
+        }
+    }
+}
+
 pub struct BitbucketWorker<'a> {
     opts: &'a BitBucketOpts,
     timeout_counter: RelaxedCounter,
 }
 
 impl BitbucketWorker<'_> {
-    pub fn new(opts: &BitBucketOpts) -> BitbucketWorker {
+    pub fn new(opts: &BitBucketOpts) -> BitbucketWorker<'_> {
         BitbucketWorker {
             opts,
             timeout_counter: RelaxedCounter::new(0),
@@ -45,17 +56,20 @@ impl BitbucketWorker<'_> {
                 u.append(&mut p);
                 Ok(u)
             }
-            (Err(GenericError { msg }), Ok(p)) => {
-                eprintln!("Failed loading user repos due to '{}'", msg);
+            (Err(user_e), Ok(p)) => {
+                eprintln!("Failed loading user repos due to '{}'", user_e.msg);
                 Ok(p)
             }
-            (Ok(u), Err(GenericError { msg })) => {
-                eprintln!("Failed loading project repos due to '{}'", msg);
+            (Ok(u), Err(project_e)) => {
+                eprintln!("Failed loading project repos due to '{}'", project_e.msg);
                 Ok(u)
             }
-            (Err(user_e), Err(project_e)) => bail(&format!(
-                "Failed loading user repos due to '{}'. Failed loading project repos due to '{}'",
-                user_e.msg, project_e.msg
+            (Err(user_e), Err(project_e)) => Err(Error::new(
+                project_e.kind,
+                &format!(
+                    "Failed loading user repos due to '{}'. Failed loading project repos due to '{}'",
+                    user_e.msg, project_e.msg
+                ),
             )),
         }
     }
@@ -66,8 +80,7 @@ impl BitbucketWorker<'_> {
             .await
         {
             Ok(all_projects) => Ok(self.fetch_all("projects", all_projects).await?),
-            Err(e) if self.opts.verbose => bail(&format!("{}\nCause: {}", e.msg, e.cause))?,
-            Err(e) => bail(&e.msg)?,
+            Err(e) => Err(e.into_error(self.opts.verbose)),
         }
     }
 
@@ -76,10 +89,96 @@ impl BitbucketWorker<'_> {
             .fetch_all_paginated::<UserResult>("users", "/rest/api/1.0/users")
             .await
         {
-            Ok(all_users) => Ok(self.fetch_all("users", all_users).await?),
-            Err(e) if self.opts.verbose => bail(&format!("{}\nCause: {}", e.msg, e.cause))?,
-            Err(e) => bail(&e.msg)?,
+            Ok(all_users) => {
+                let users: Vec<UserResult> = match self.opts.inactive_users {
+                    InactiveUsersPolicy::Skip => {
+                        all_users.into_iter().filter(|u| u.active).collect()
+                    }
+                    InactiveUsersPolicy::Include => all_users,
+                };
+                Ok(self.fetch_all("users", users).await?)
+            }
+            Err(e) => Err(e.into_error(self.opts.verbose)),
+        }
+    }
+
+    /// Tag repos with the labels selected by --label and --exclude-label.
+    pub async fn attach_labels(&self, mut repos: Vec<Repo>) -> Result<Vec<Repo>> {
+        for label in self.opts.labels.iter().chain(&self.opts.exclude_labels) {
+            let label = label.to_lowercase();
+            let path = format!(
+                "/rest/api/1.0/labels/{}/labeled?type=REPOSITORY",
+                encode_path_segment(&label)
+            );
+            let labeled: Vec<Labeled> = match self.fetch_all_paginated("labels", &path).await {
+                Ok(labeled) => labeled,
+                Err(e) => {
+                    return Err(e
+                        .into_error(self.opts.verbose)
+                        .context(&format!("Failed fetching repos labeled {}.", label)))
+                }
+            };
+            let names: HashSet<String> = labeled
+                .iter()
+                .map(|l| format!("{}/{}", l.project.key, l.slug).to_lowercase())
+                .collect();
+            for repo in repos.iter_mut() {
+                if names.contains(&repo.full_name()) && !repo.labels.contains(&label) {
+                    repo.labels.push(label.clone());
+                }
+            }
         }
+        Ok(repos)
+    }
+
+    /// Fetch all labels of every repo, one request per repo.
+    pub async fn fetch_repo_labels(&self, repos: Vec<Repo>) -> Vec<Repo> {
+        stream::iter(repos.into_iter().map(|mut repo| async move {
+            let path = format!(
+                "/rest/api/1.0/projects/{}/repos/{}/labels",
+                repo.original_project_key, repo.original_name
+            );
+            match self.fetch_all_paginated::<Label>("labels", &path).await {
+                Ok(labels) => repo.labels = labels.into_iter().map(|l| l.name).collect(),
+                Err(e) if self.opts.verbose => eprintln!("{} Cause: {}", e.msg, e.cause),
+                Err(_) => {}
+            }
+            repo
+        }))
+        .buffer_unordered(self.opts.concurrency.into())
+        .collect::<Vec<Repo>>()
+        .await
+    }
+
+    /// Fill in the default branch of the named repos where the repo listing didn't have it,
+    /// one request per repo.
+    pub async fn attach_default_branches(&self, repos: Vec<Repo>, names: &[String]) -> Vec<Repo> {
+        stream::iter(repos.into_iter().map(|mut repo| async move {
+            if repo.default_branch.is_none() && names.contains(&repo.full_name()) {
+                let path = format!(
+                    "/rest/api/1.0/projects/{}/repos/{}/default-branch",
+                    repo.original_project_key, repo.original_name
+                );
+                match self.fetch_one::<Branch>("default branch", &path).await {
+                    Ok(branch) => repo.default_branch = Some(branch.display_id),
+                    Err(e) if self.opts.verbose => eprintln!("{} Cause: {}", e.msg, e.cause),
+                    Err(_) => {}
+                }
+            }
+            repo
+        }))
+        .buffer_unordered(self.opts.concurrency.into())
+        .collect::<Vec<Repo>>()
+        .await
+    }
+
+    async fn fetch_one<T>(&self, naming: &str, path: &str) -> BitbucketResult<T>
+    where
+        T: DeserializeOwned,
+    {
+        let host = self.opts.server.clone().unwrap();
+        let url = format!("{}{}", host.trim_end_matches('/'), path);
+        self.fetch_with_retries(naming, &url).await
     }
 
     async fn fetch_all<T>(&self, naming: &str, all_projects: Vec<T>) -> Result<Vec<Repo>>
@@ -148,61 +247,65 @@ impl BitbucketWorker<'_> {
         }
         let mut start: u32 = 0;
         let mut sum: Vec<T> = vec![];
-        'outer: loop {
+        loop {
             let url = format!(
-                "{host}{path}?limit=500&start={start}",
+                "{host}{path}{separator}limit=500&start={start}",
                 host = host,
                 path = path,
+                separator = if path.contains('?') { '&' } else { '?' },
                 start = start
             );
-            for attempt in 1..self.opts.retries + 2 {
-                let request_builder: Result<RequestBuilder> = self.bake_client(&url);
-                if request_builder.is_err() {
+            let mut resp: PageResponse<T> = self.fetch_with_retries(naming, &url).await?;
+            sum.append(resp.values.borrow_mut());
+            if resp.is_last_page {
+                break;
+            }
+            start += resp.size;
+        }
+        Ok(sum)
+    }
+
+    /// Fetch one response, retrying timeouts.
+    async fn fetch_with_retries<T>(&self, naming: &str, url: &str) -> BitbucketResult<T>
+    where
+        T: DeserializeOwned,
+    {
+        for attempt in 1..self.opts.retries + 2 {
+            let request_builder: RequestBuilder = match self.bake_client(url) {
+                Ok(request_builder) => request_builder,
+                Err(e) => {
                     return Err(BitbucketError {
-                        is_timeout: false,
+                        kind: e.kind,
                         msg: "Failed generating bitbucket client request".to_owned(),
-                        cause: request_builder.err().unwrap().msg,
-                    });
+                        cause: e.msg,
+                    })
                 }
-                let response: reqwest::Result<reqwest::Response> =
-                    request_builder.unwrap().send().await;
-                match extract_body::<PageResponse<T>>(response, naming).await {
-                    Ok(mut resp) => {
-                        sum.append(resp.values.borrow_mut());
-                        if resp.is_last_page {
-                            break 'outer;
-                        } else {
-                            start += resp.size;
-                            continue 'outer;
-                        }
-                    }
-                    Err(e) if e.is_timeout => {
-                        let count: u64 = self.timeout_counter.inc() as u64;
-                        if attempt > self.opts.retries {
-                            // Last chance blown!
-                            return Err(e);
-                        } else if let Some(Some(backoff)) =
-                            self.opts.backoff_sec.map(|b| b.checked_mul(count + 1))
-                        {
-                            tokio::time::sleep(Duration::from_secs(backoff)).await;
-                        }
-                    }
-                    Err(e) => {
+            };
+            let response: reqwest::Result<reqwest::Response> = request_builder.send().await;
+            match extract_body::<T>(response, naming).await {
+                Err(e) if e.kind == ErrorKind::Timeout => {
+                    let count: u64 = self.timeout_counter.inc() as u64;
+                    if attempt > self.opts.retries {
+                        // Last chance blown!
                         return Err(e);
+                    } else if let Some(Some(backoff)) =
+                        self.opts.backoff_sec.map(|b| b.checked_mul(count + 1))
+                    {
+                        tokio::time::sleep(Duration::from_secs(backoff)).await;
                     }
                 }
+                result => return result,
             }
-            // To be sure we dont escape some case into an endless retry-loop
-            return Err(BitbucketError {
-                is_timeout: true,
-                msg: format!(
-                    "Failed to read from bitbucket with {} retries.",
-                    self.opts.retries
-                ),
-                cause: "Timeouts against bitbucket.".to_owned(),
-            });
         }
-        Ok(sum)
+        // To be sure we dont escape some case into an endless retry-loop
+        Err(BitbucketError {
+            kind: ErrorKind::Timeout,
+            msg: format!(
+                "Failed to read from bitbucket with {} retries.",
+                self.opts.retries
+            ),
+            cause: "Timeouts against bitbucket.".to_owned(),
+        })
     }
 
     async fn fetch_one_project<T>(&self, project: &T) -> BitbucketResult<Vec<Repo>>
@@ -229,6 +332,19 @@ impl BitbucketWorker<'_> {
     }
 }
 
+/// Percent-encode all but the unreserved characters, for use as one segment of a URL path.
+fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
 async fn extract_body<T>(
     response: reqwest::Result<reqwest::Response>,
     naming: &str,
@@ -240,12 +356,12 @@ where
         Ok(response) if response.status().is_success() => match response.json::<T>().await {
             Ok(all_projects) => Ok(all_projects),
             Err(e) if e.is_timeout() => Err(BitbucketError {
-                is_timeout: true,
+                kind: ErrorKind::Timeout,
                 msg: "Timeout reading from bitbucket.".to_owned(),
                 cause: format!("{:?}", e),
             }),
             Err(e) => Err(BitbucketError {
-                is_timeout: e.is_timeout(),
+                kind: ErrorKind::Decode,
                 msg: format!(
                     "Failed fetching {} from bitbucket, bad json format.",
                     naming
@@ -254,7 +370,7 @@ where
             }),
         },
         Ok(response) => Err(BitbucketError {
-            is_timeout: false,
+            kind: ErrorKind::from_status(response.status().as_u16()),
             msg: format!(
                 "Failed fetching {} from bitbucket, status code: {}.",
                 naming,
@@ -266,7 +382,7 @@ where
             },
         }),
         Err(e) => Err(BitbucketError {
-            is_timeout: e.is_timeout(),
+            kind: ErrorKind::from_reqwest(&e),
             msg: format!("Failed fetching {} from bitbucket.", naming),
             cause: format!("{:?}", e),
         }),
@@ -279,7 +395,7 @@ mod tests {
     use rand::{thread_rng, Rng};
 
     use crate::bitbucket::types::ProjDesc;
-    use crate::types::CloneType;
+    use crate::types::test_bitbucket_opts;
 
     use super::*;
 
@@ -295,23 +411,15 @@ mod tests {
 
     fn basic_opts() -> BitBucketOpts {
         BitBucketOpts {
-            username: None,
-            password: None,
-            password_from_env: false,
-            concurrency: 1,
             verbose: true,
-            https_allow_anything: false,
             server: Some(format!(
                 "http://{host}.p2/{path}",
                 host = random_string(12),
                 path = random_string(12)
             )),
-            clone_type: CloneType::HTTP,
             project_keys: vec!["key".to_owned()],
-            all: false,
             timeout_sec: 10,
-            retries: 0,
-            backoff_sec: None,
+            ..test_bitbucket_opts()
         }
     }
 
@@ -320,6 +428,7 @@ mod tests {
         // given
         let project: ProjDesc = ProjDesc {
             key: "key".to_owned(),
+            name: String::new(),
         };
         let bit_bucket_opts = basic_opts();
         let worker = BitbucketWorker::new(&bit_bucket_opts);
@@ -350,6 +459,7 @@ mod tests {
         // given
         let project: ProjDesc = ProjDesc {
             key: "KEY".to_owned(),
+            name: String::new(),
         };
         let mut bit_bucket_opts = basic_opts();
         bit_bucket_opts.server = Some("http://bitbucket.com/This_Will_Never_Work".to_owned());
@@ -362,10 +472,17 @@ mod tests {
         match result {
             Ok(_) => panic!("This request was expected to fail."),
             Err(e) => assert!(
-                e.msg.contains("status code: 404"),
+                e.kind == ErrorKind::Http(404) && e.msg.contains("status code: 404"),
                 "Response code should be 404, but was {:?}",
                 e.cause
             ),
         }
     }
+
+    #[test]
+    fn test_encode_path_segment() {
+        assert_eq!(encode_path_segment("team-a_1.x~"), "team-a_1.x~");
+        assert_eq!(encode_path_segment("ci/cd #1"), "ci%2Fcd%20%231");
+        assert_eq!(encode_path_segment("på"), "p%C3%A5");
+    }
 }
@@ -1,9 +1,12 @@
-use generic_error::Result;
-
-use crate::util::bail;
 use crate::{
-    bitbucket::types::Repo, bitbucket::worker::BitbucketWorker, git::Git, input::select_projects,
-    types::CloneOpts,
+    bitbucket::types::{with_saved_login, Repo},
+    bitbucket::worker::BitbucketWorker,
+    error::Result,
+    filter::RepoFilter,
+    git::{layout::Layout, Git},
+    input::{get_bool, prompts::PROMPT_GIT_RECLONE_BROKEN, select_repo_set, sets::get_set},
+    state::{RepoStatus, RunState},
+    types::{CloneOpts, Exit},
 };
 
 pub struct Cloner {
@@ -17,43 +20,52 @@ impl Cloner {
         Ok(Cloner { opts })
     }
 
-    pub async fn clone_projects_and_users(self) -> Result<()> {
+    pub async fn clone_projects_and_users(self) -> Result<Exit> {
+        if self.opts.resuming() {
+            return self.clone_saved().await;
+        }
         let bb = BitbucketWorker::new(&self.opts.bitbucket_opts);
         let repos: Vec<Repo> = match bb.fetch_all_repos().await {
             Ok(r) => r,
-            Err(e) => bail(&format!("Failed fetching user & project repos. {}", e.msg))?,
+            Err(e) => Err(e.context("Failed fetching user & project repos."))?,
         };
-        self.clone_repos(repos).await?;
-        Ok(())
+        let repos = bb.attach_labels(repos).await?;
+        self.clone_repos(repos).await
     }
 
-    pub async fn clone_projects(self) -> Result<()> {
+    pub async fn clone_projects(self) -> Result<Exit> {
+        if self.opts.resuming() {
+            return self.clone_saved().await;
+        }
         let bb = BitbucketWorker::new(&self.opts.bitbucket_opts);
         let repos: Vec<Repo> = match bb.fetch_all_project_repos().await {
             Ok(r) => r,
-            Err(e) => bail(&format!("Failed fetching project repos. {}", e.msg))?,
+            Err(e) => Err(e.context("Failed fetching project repos."))?,
         };
-        self.clone_repos(repos).await?;
-        Ok(())
+        let repos = bb.attach_labels(repos).await?;
+        self.clone_repos(repos).await
     }
 
-    pub async fn clone_users(self) -> Result<()> {
+    pub async fn clone_users(self) -> Result<Exit> {
+        if self.opts.resuming() {
+            return self.clone_saved().await;
+        }
         let bb = BitbucketWorker::new(&self.opts.bitbucket_opts);
         let repos: Vec<Repo> = match bb.fetch_all_user_repos().await {
             Ok(r) => r,
-            Err(e) => bail(&format!("Failed fetching user repos. {}", e.msg))?,
+            Err(e) => Err(e.context("Failed fetching user repos."))?,
         };
-        self.clone_repos(repos).await?;
-        Ok(())
+        let repos = bb.attach_labels(repos).await?;
+        self.clone_repos(repos).await
     }
 
-    async fn clone_repos(self, mut repos: Vec<Repo>) -> Result<()> {
-        let mut project_keys = self.opts.bitbucket_opts.project_keys();
-        if self.opts.interactive() && !self.opts.bitbucket_opts.all && project_keys.is_empty() {
-            project_keys = select_projects(&repos);
-        }
-
-        if !self.opts.bitbucket_opts.all && !project_keys.is_empty() {
+    async fn clone_repos(self, repos: Vec<Repo>) -> Result<Exit> {
+        let bb_opts = &self.opts.bitbucket_opts;
+        let mut repos = RepoFilter::new(bb_opts)?.apply(repos);
+        let project_keys = bb_opts.project_keys();
+        if let Some(name) = &bb_opts.set {
+            repos = get_set(name)?.apply(repos)?;
+        } else if !bb_opts.all && !project_keys.is_empty() {
             let mut tmp_vec = Vec::new();
             for r in repos {
                 if project_keys.contains(&r.project_key) {
@@ -61,16 +73,92 @@ impl Cloner {
                 }
             }
             repos = tmp_vec;
+        } else if self.opts.interactive() && !bb_opts.has_selection() {
+            repos = select_repo_set(&repos)?.apply(repos)?;
+        }
+        if self.opts.interactive() {
+            println!("Selected {} repos.", repos.len());
+            for line in self.opts.bitbucket_opts.filter_summary() {
+                println!("  {}", line);
+            }
+        }
+        Layout::new(&self.opts.git_opts).check_collisions(&repos)?;
+        // Clones that know origin/HEAD take the default branch from there.
+        let unknown = Git::new(&repos, &self.opts.git_opts).without_origin_head();
+        let repos = BitbucketWorker::new(bb_opts)
+            .attach_default_branches(repos, &unknown)
+            .await;
+        if self.opts.git_opts.dry_run {
+            return self.work(repos).await;
+        }
+        if let Err(e) = RunState::open(&self.opts.git_opts.output_directory)
+            .and_then(|s| s.save_inventory(&repos))
+        {
+            eprintln!(
+                "Unable to save the repo list, --resume will not work. {}",
+                e.msg
+            );
         }
-        Git::new(&repos, &self.opts.git_opts).git_going().await;
+        self.work(repos).await
+    }
+
+    /// Work the repos the last run left over, from the repo list saved by that run.
+    async fn clone_saved(self) -> Result<Exit> {
+        let mut statuses = vec![];
+        if self.opts.resume {
+            statuses.push(RepoStatus::Skipped);
+        }
+        if self.opts.retry_failed {
+            statuses.push(RepoStatus::Failed);
+        }
+        let bb_opts = &self.opts.bitbucket_opts;
+        let repos: Vec<Repo> = RunState::open(&self.opts.git_opts.output_directory)?
+            .select(&statuses)?
+            .into_iter()
+            .map(|r| Repo {
+                git: with_saved_login(&r.git, &bb_opts.clone_type, bb_opts),
+                push_url: match (&r.push_url, &bb_opts.push_clone_type) {
+                    (Some(url), Some(clone_type)) => {
+                        Some(with_saved_login(url, clone_type, bb_opts))
+                    }
+                    (push_url, _) => push_url.clone(),
+                },
+                ..r
+            })
+            .collect();
+        if repos.is_empty() {
+            println!("Nothing left over from the last run.");
+            return Ok(Exit::Success);
+        }
+        println!("Continuing with {} repos from the last run.", repos.len());
+        self.work(repos).await
+    }
 
-        Ok(())
+    async fn work(mut self, repos: Vec<Repo>) -> Result<Exit> {
+        if self.opts.interactive() && !self.opts.git_opts.reclone_broken {
+            let broken = Git::new(&repos, &self.opts.git_opts).broken_clones().await;
+            if !broken.is_empty() {
+                println!("{} directories are not clones of their repo:", broken.len());
+                for (path, reason) in &broken {
+                    println!("  {} ({})", path, reason);
+                }
+                self.opts.git_opts.reclone_broken = get_bool(&PROMPT_GIT_RECLONE_BROKEN, false);
+            }
+        }
+        let tally = Git::new(&repos, &self.opts.git_opts).git_going().await;
+        Ok(if tally.interrupted {
+            Exit::Interrupted
+        } else if self.opts.fail_threshold.exceeded(tally.failed, tally.repos) {
+            Exit::GitFailures
+        } else {
+            Exit::Success
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::types::{BitBucketOpts, CloneType, GitOpts};
+    use crate::types::{test_bitbucket_opts, test_opts, BitBucketOpts, FailThreshold};
 
     use super::*;
 
@@ -78,32 +166,30 @@ mod tests {
     async fn cloner_integration_test() {
         let opts = CloneOpts {
             batch_mode: true,
+            resume: false,
+            retry_failed: false,
+            fail_threshold: FailThreshold::Count(0),
             bitbucket_opts: BitBucketOpts {
                 server: Some("https://github.com".to_owned()),
                 verbose: true,
-                https_allow_anything: false,
-                concurrency: 1,
                 password: Some("PA$$WoRD123#%&".to_owned()),
-                password_from_env: false,
                 username: Some("Admin".to_owned()),
-                clone_type: CloneType::HTTP,
-                project_keys: vec![],
                 all: true,
-                timeout_sec: 5,
                 retries: 2,
-                backoff_sec: None,
-            },
-            git_opts: GitOpts {
-                reset_state: false,
-                concurrency: 1,
-                quiet: false,
-                ssl_allow_anything: false,
-                output_directory: ".".to_owned(),
+                ..test_bitbucket_opts()
             },
+            git_opts: test_opts("."),
         };
         match Cloner::new(opts).unwrap().clone_projects().await {
-            Ok(_) => panic!("GitHub.com should never be available as a bitbucket server"),
-            Err(e) => println!("{}", e.msg),
+            Ok(exit) => panic!(
+                "GitHub.com should never be available as a bitbucket server, exited {:?}",
+                exit
+            ),
+            Err(e) => assert!(
+                matches!(Exit::from_error(&e), Exit::Inventory | Exit::Auth),
+                "{:?}",
+                e
+            ),
         }
     }
 }
@@ -2,11 +2,11 @@ use std::path::Path;
 
 use clap::Shell;
 use dialoguer::{Confirm, Input, Select};
-use generic_error::Result;
 use structopt::StructOpt;
 
+use crate::error::{ErrorKind, Result};
 use crate::types::Opts;
-use crate::util::bail;
+use crate::util::fail;
 
 #[cfg(target_os = "windows")]
 const HOME_VAR: &str = "USERPROFILE";
@@ -35,10 +35,10 @@ fn confirm(question: &str) -> Result<bool> {
         .interact()
     {
         Ok(ans) => Ok(ans),
-        Err(e) => bail(&format!(
-            "Failed getting answer for question due to {:?}",
-            e
-        )),
+        Err(e) => fail(
+            ErrorKind::Io,
+            &format!("Failed getting answer for question due to {:?}", e),
+        ),
     }
 }
 
@@ -57,15 +57,19 @@ fn create_dir_if_necessary(output_dir: &str) -> Result<()> {
                     println!("Directory created.");
                     Ok(())
                 }
-                Err(e) => bail(&format!(
-                    "Failed creating dir {} due to {:?}",
-                    output_dir, e
-                )),
+                Err(e) => fail(
+                    ErrorKind::Io,
+                    &format!("Failed creating dir {} due to {:?}", output_dir, e),
+                ),
             },
-            Ok(_ans) => {
-                bail("Cannot proceed writing to output_directory without creating it first.")
-            }
-            Err(e) => bail(&format!("Failed reading input due to {:?}", e)),
+            Ok(_ans) => fail(
+                ErrorKind::Config,
+                "Cannot proceed writing to output_directory without creating it first.",
+            ),
+            Err(e) => fail(
+                ErrorKind::Io,
+                &format!("Failed reading input due to {:?}", e),
+            ),
         }
     } else {
         Ok(())
@@ -82,7 +86,10 @@ fn get_output_dir(shell: Shell) -> Result<String> {
             create_dir_if_necessary(&output_dir)?;
             Ok(output_dir)
         }
-        Err(e) => bail(&format!("Failed interpreting prompt due to {:?}", e)),
+        Err(e) => fail(
+            ErrorKind::Io,
+            &format!("Failed interpreting prompt due to {:?}", e),
+        ),
     }
 }
 
@@ -98,10 +105,16 @@ fn get_shell_selection() -> Result<Shell> {
             let shell_str = variants1[shell_idx];
             match shell_str.parse() {
                 Ok(shell) => Ok(shell),
-                Err(e) => bail(&format!("Failed parsing shell selection due to {}", e)),
+                Err(e) => fail(
+                    ErrorKind::Config,
+                    &format!("Failed parsing shell selection due to {}", e),
+                ),
             }
         }
-        Err(e) => bail(&format!("Failed determining selection due to {:?}", e)),
+        Err(e) => fail(
+            ErrorKind::Io,
+            &format!("Failed determining selection due to {:?}", e),
+        ),
     }
 }
 
@@ -122,7 +135,7 @@ fn get_default_completion_location(shell: Shell) -> Option<String> {
 
 fn join_path(parts: &[&str]) -> Option<String> {
     let mut path = Path::new(parts[0]).to_path_buf();
-    for part in parts[1..].to_vec() {
+    for part in parts[1..].iter().copied() {
         path.push(part);
     }
     path.to_str().map(|s| s.to_owned())
@@ -0,0 +1,181 @@
+use std::fmt;
+
+/// What went wrong, so that exit codes, retries and reports don't have to parse messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// Bitbucket answered with an unexpected status code.
+    Http(u16),
+    /// Bitbucket refused the login, with 401 or 403.
+    Auth(u16),
+    Tls,
+    Network,
+    Timeout,
+    Decode,
+    Git(GitFailure),
+    Io,
+    Config,
+    Cancelled,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GitFailure {
+    /// Trouble on the way to the server, that may well be gone when tried again.
+    Network,
+    Auth,
+    NotFound,
+    /// Local work or history in the way, like a branch that can't be fast-forwarded.
+    Conflict,
+    /// The directory is not a clone of the repo.
+    Broken,
+    Other,
+}
+
+#[derive(Clone, Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub msg: String,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn new(kind: ErrorKind, msg: &str) -> Error {
+        Error {
+            kind,
+            msg: msg.to_owned(),
+        }
+    }
+
+    /// The same error, with what was being done in front of the message.
+    pub fn context(self, context: &str) -> Error {
+        Error {
+            kind: self.kind,
+            msg: format!("{} {}", context, self.msg),
+        }
+    }
+}
+
+impl ErrorKind {
+    /// Name of the kind, as written to reports.
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Http(_) => "http",
+            ErrorKind::Auth(_) => "auth",
+            ErrorKind::Tls => "tls",
+            ErrorKind::Network => "network",
+            ErrorKind::Timeout => "timed_out",
+            ErrorKind::Decode => "decode",
+            ErrorKind::Git(GitFailure::Network) => "git_network",
+            ErrorKind::Git(GitFailure::Auth) => "git_auth",
+            ErrorKind::Git(GitFailure::NotFound) => "git_not_found",
+            ErrorKind::Git(GitFailure::Conflict) => "git_conflict",
+            ErrorKind::Git(GitFailure::Broken) => "git_broken",
+            ErrorKind::Git(GitFailure::Other) => "git",
+            ErrorKind::Io => "io",
+            ErrorKind::Config => "config",
+            ErrorKind::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn from_status(status: u16) -> ErrorKind {
+        match status {
+            401 | 403 => ErrorKind::Auth(status),
+            _ => ErrorKind::Http(status),
+        }
+    }
+
+    pub fn from_reqwest(e: &reqwest::Error) -> ErrorKind {
+        if e.is_timeout() {
+            ErrorKind::Timeout
+        } else if e.is_decode() {
+            ErrorKind::Decode
+        } else if let Some(status) = e.status() {
+            ErrorKind::from_status(status.as_u16())
+        } else if is_tls(e) {
+            ErrorKind::Tls
+        } else {
+            ErrorKind::Network
+        }
+    }
+}
+
+/// Whether a request failed on certificates or the handshake, which reqwest doesn't tell directly.
+/// The TLS error is somewhere down the sources, possibly wrapped in an io::Error.
+fn is_tls(e: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(e);
+    while let Some(error) = source {
+        let inner = error
+            .downcast_ref::<std::io::Error>()
+            .and_then(|io| io.get_ref());
+        if error.is::<native_tls::Error>() || inner.is_some_and(|i| i.is::<native_tls::Error>()) {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::new(ErrorKind::Io, &e.to_string())
+    }
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(e: std::str::Utf8Error) -> Self {
+        Error::new(ErrorKind::Decode, &e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::new(ErrorKind::from_reqwest(&e), &e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kinds() {
+        assert_eq!(ErrorKind::from_status(401), ErrorKind::Auth(401));
+        assert_eq!(ErrorKind::from_status(404), ErrorKind::Http(404));
+        let io: Error = std::io::Error::new(std::io::ErrorKind::NotFound, "gone").into();
+        assert_eq!(io.kind, ErrorKind::Io);
+        let e = Error::new(ErrorKind::Timeout, "timed out after 5s").context("plat/api failed.");
+        assert_eq!(e.kind.name(), "timed_out");
+        assert_eq!(e.msg, "plat/api failed. timed out after 5s");
+    }
+
+    #[tokio::test]
+    async fn test_tls_kind() {
+        // A server that doesn't speak TLS fails the handshake.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let _ = socket.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await;
+            }
+        });
+        let handshake = reqwest::get(format!("https://127.0.0.1:{}/", port))
+            .await
+            .unwrap_err();
+        assert_eq!(ErrorKind::from_reqwest(&handshake), ErrorKind::Tls);
+
+        // Only the error types count, not a host named like it.
+        let unresolved = reqwest::get("https://tls-certificate.invalid/")
+            .await
+            .unwrap_err();
+        assert_eq!(ErrorKind::from_reqwest(&unresolved), ErrorKind::Network);
+    }
+}
@@ -0,0 +1,191 @@
+use regex::Regex;
+
+use crate::bitbucket::types::Repo;
+use crate::error::{ErrorKind, Result};
+use crate::types::BitBucketOpts;
+use crate::util::fail;
+
+const REGEX_PREFIX: &str = "regex:";
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Glob(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn parse(raw: &str) -> Result<Pattern> {
+        match raw.strip_prefix(REGEX_PREFIX) {
+            Some(expr) => match Regex::new(&format!("(?i){}", expr)) {
+                Ok(regex) => Ok(Pattern::Regex(regex)),
+                Err(e) => fail(
+                    ErrorKind::Config,
+                    &format!("Bad regex filter '{}'. {}", raw, e),
+                ),
+            },
+            None => Ok(Pattern::Glob(raw.to_lowercase())),
+        }
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        match self {
+            Pattern::Glob(glob) => glob_match(glob, &text.to_lowercase()),
+            Pattern::Regex(regex) => regex.is_match(text),
+        }
+    }
+
+    /// Patterns containing a '/' are matched against 'project/slug', others against the slug only.
+    pub fn matches_repo(&self, repo: &Repo) -> bool {
+        let raw = match self {
+            Pattern::Glob(glob) => glob.as_str(),
+            Pattern::Regex(regex) => regex.as_str(),
+        };
+        if raw.contains('/') {
+            self.matches(&repo.full_name())
+        } else {
+            self.matches(&repo.name)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RepoFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    exclude_project: Vec<Pattern>,
+    labels: Vec<String>,
+    exclude_labels: Vec<String>,
+}
+
+impl RepoFilter {
+    pub fn new(opts: &BitBucketOpts) -> Result<RepoFilter> {
+        Ok(RepoFilter {
+            include: parse_all(&opts.include)?,
+            exclude: parse_all(&opts.exclude)?,
+            exclude_project: parse_all(&opts.exclude_project)?,
+            labels: lowercase_all(&opts.labels),
+            exclude_labels: lowercase_all(&opts.exclude_labels),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty()
+            && self.exclude.is_empty()
+            && self.exclude_project.is_empty()
+            && self.labels.is_empty()
+            && self.exclude_labels.is_empty()
+    }
+
+    pub fn matches(&self, repo: &Repo) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches_repo(repo)))
+            && !self.exclude.iter().any(|p| p.matches_repo(repo))
+            && !self
+                .exclude_project
+                .iter()
+                .any(|p| p.matches(&repo.project_key))
+            && (self.labels.is_empty() || repo.labels.iter().any(|l| self.labels.contains(l)))
+            && !repo.labels.iter().any(|l| self.exclude_labels.contains(l))
+    }
+
+    pub fn apply(&self, repos: Vec<Repo>) -> Vec<Repo> {
+        if self.is_empty() {
+            return repos;
+        }
+        repos.into_iter().filter(|r| self.matches(r)).collect()
+    }
+}
+
+fn parse_all(raw: &[String]) -> Result<Vec<Pattern>> {
+    raw.iter().map(|r| Pattern::parse(r)).collect()
+}
+
+fn lowercase_all(raw: &[String]) -> Vec<String> {
+    raw.iter().map(|r| r.to_lowercase()).collect()
+}
+
+/// Plain glob matching, where '*' matches any sequence of characters and '?' a single one.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::test_repo as repo;
+
+    use super::*;
+
+    fn repo_with_labels(project_key: &str, name: &str, labels: &[&str]) -> Repo {
+        let mut repo = repo(project_key, name);
+        repo.labels = labels.iter().map(|l| l.to_string()).collect();
+        repo
+    }
+
+    fn filter(include: &[&str], exclude: &[&str], exclude_project: &[&str]) -> RepoFilter {
+        let parse = |raw: &[&str]| -> Vec<Pattern> {
+            raw.iter().map(|r| Pattern::parse(r).unwrap()).collect()
+        };
+        RepoFilter {
+            include: parse(include),
+            exclude: parse(exclude),
+            exclude_project: parse(exclude_project),
+            labels: vec![],
+            exclude_labels: vec![],
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("plat/*-service", "plat/user-service"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a?c", "abc"));
+        assert!(!glob_match("plat/*-service", "plat/user-service-old"));
+        assert!(!glob_match("~*", "plat"));
+    }
+
+    #[test]
+    fn test_filter_include_exclude() {
+        let f = filter(&["PLAT/*-service"], &["*/legacy-*"], &["~*"]);
+        assert!(f.matches(&repo("plat", "user-service")));
+        assert!(!f.matches(&repo("plat", "legacy-service")));
+        assert!(!f.matches(&repo("plat", "frontend")));
+        assert!(!f.matches(&repo("~jensim", "user-service")));
+    }
+
+    #[test]
+    fn test_filter_regex() {
+        let f = filter(&["regex:^plat/(user|order)-"], &[], &[]);
+        assert!(f.matches(&repo("plat", "order-service")));
+        assert!(!f.matches(&repo("plat", "payment-service")));
+        assert!(Pattern::parse("regex:(").is_err());
+    }
+
+    #[test]
+    fn test_filter_labels() {
+        let mut f = filter(&["plat/*"], &[], &[]);
+        f.labels = vec!["team-x".to_owned(), "library".to_owned()];
+        f.exclude_labels = vec!["deprecated".to_owned()];
+        assert!(f.matches(&repo_with_labels("plat", "a", &["team-x"])));
+        assert!(!f.matches(&repo_with_labels("plat", "b", &["team-x", "deprecated"])));
+        assert!(!f.matches(&repo_with_labels("plat", "c", &[])));
+        assert!(!f.matches(&repo_with_labels("app", "d", &["library"])));
+    }
+}
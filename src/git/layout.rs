@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+use crate::bitbucket::types::Repo;
+use crate::error::{ErrorKind, Result};
+use crate::types::GitOpts;
+use crate::util::fail;
+
+const PLACEHOLDERS: [&str; 4] = ["{project}", "{project_name}", "{repo}", "{user}"];
+const UNSAFE_CHARS: [char; 11] = ['/', '\\', ':', '*', '?', '"', '<', '>', '|', '$', '`'];
+
+/// Where clones land below the output directory, rendered from a template like '{project}/{repo}'.
+pub struct Layout<'a> {
+    template: &'a str,
+    user_template: &'a str,
+    keep_case: bool,
+}
+
+impl Layout<'_> {
+    pub fn new(opts: &GitOpts) -> Layout<'_> {
+        Layout {
+            template: &opts.layout,
+            user_template: opts.user_layout.as_deref().unwrap_or(&opts.layout),
+            keep_case: opts.keep_case,
+        }
+    }
+
+    pub fn validate_template(template: &str) -> Result<()> {
+        let mut rest = template.to_owned();
+        for placeholder in &PLACEHOLDERS {
+            rest = rest.replace(placeholder, "");
+        }
+        if !template.contains("{repo}") {
+            fail(
+                ErrorKind::Config,
+                &format!("Layout '{}' must contain {{repo}}", template),
+            )
+        } else if rest.contains('{') || rest.contains('}') {
+            fail(
+                ErrorKind::Config,
+                &format!(
+                    "Layout '{}' has an unknown placeholder, use {}",
+                    template,
+                    PLACEHOLDERS.join(", ")
+                ),
+            )
+        } else if template.starts_with('/')
+            || template.starts_with('\\')
+            || template.split(['/', '\\']).any(|p| p == "..")
+        {
+            fail(
+                ErrorKind::Config,
+                &format!(
+                    "Layout '{}' must stay inside the output directory",
+                    template
+                ),
+            )
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Path of the clone, relative to the output directory.
+    pub fn relative_path(&self, repo: &Repo) -> String {
+        let (key, name) = if self.keep_case {
+            (&repo.original_project_key, &repo.original_name)
+        } else {
+            (&repo.project_key, &repo.name)
+        };
+        let template = if key.starts_with('~') {
+            self.user_template
+        } else {
+            self.template
+        };
+        let project_name = match (repo.project_name.trim(), self.keep_case) {
+            ("", _) => key.to_owned(),
+            (project_name, true) => project_name.to_owned(),
+            (project_name, false) => project_name.to_lowercase(),
+        };
+        let rendered = template
+            .replace("{project_name}", &sanitize(&project_name))
+            .replace("{project}", &sanitize(key))
+            .replace("{user}", &sanitize(key.trim_start_matches('~')))
+            .replace("{repo}", &sanitize(name));
+        // Leading dots are replaced too, so that a name like '..' stays inside the output directory.
+        rendered
+            .split('/')
+            .map(|part| {
+                let dots = part.len() - part.trim_start_matches('.').len();
+                format!("{}{}", "_".repeat(dots), &part[dots..])
+            })
+            .collect::<Vec<String>>()
+            .join("/")
+    }
+
+    /// Fail if two repos would be cloned into the same directory,
+    /// ignoring case as not all file systems are case sensitive.
+    pub fn check_collisions(&self, repos: &[Repo]) -> Result<()> {
+        let mut paths: HashMap<String, &Repo> = HashMap::new();
+        let mut collisions: Vec<String> = vec![];
+        for repo in repos {
+            let path = self.relative_path(repo);
+            if let Some(other) = paths.insert(path.to_lowercase(), repo) {
+                collisions.push(format!(
+                    "{} and {} -> {}",
+                    other.full_name(),
+                    repo.full_name(),
+                    path
+                ));
+            }
+        }
+        if collisions.is_empty() {
+            Ok(())
+        } else {
+            fail(
+                ErrorKind::Config,
+                &format!(
+                    "Directory layout maps several repos to the same path:\n{}",
+                    collisions.join("\n")
+                ),
+            )
+        }
+    }
+}
+
+fn sanitize(part: &str) -> String {
+    part.trim()
+        .chars()
+        .map(|c| if UNSAFE_CHARS.contains(&c) { '_' } else { c })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::test_repo;
+
+    use super::*;
+
+    fn repo(project_key: &str, project_name: &str, name: &str) -> Repo {
+        Repo {
+            project_name: project_name.to_owned(),
+            ..test_repo(project_key, name)
+        }
+    }
+
+    fn layout<'a>(template: &'a str, user_template: &'a str, keep_case: bool) -> Layout<'a> {
+        Layout {
+            template,
+            user_template,
+            keep_case,
+        }
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let plat = repo("PLAT", "Platform Team", "User-Service");
+        let user = repo("~JENSIM", "Jens Brimfors", "dotfiles");
+
+        let default = layout("{project}/{repo}", "users/{user}/{repo}", false);
+        assert_eq!(default.relative_path(&plat), "plat/user-service");
+        assert_eq!(default.relative_path(&user), "users/jensim/dotfiles");
+
+        let named = layout("{project_name}/{repo}", "{project}-{repo}", true);
+        assert_eq!(named.relative_path(&plat), "Platform Team/User-Service");
+        assert_eq!(named.relative_path(&user), "~JENSIM-dotfiles");
+
+        let unnamed = repo("PLAT", "", "api");
+        assert_eq!(named.relative_path(&unnamed), "PLAT/api");
+
+        let dots = repo("PLAT", "..", ".config");
+        assert_eq!(named.relative_path(&dots), "__/_config");
+    }
+
+    #[test]
+    fn test_validate_template() {
+        assert!(Layout::validate_template("{project}/{repo}").is_ok());
+        assert!(Layout::validate_template("users/{user}/{repo}").is_ok());
+        assert!(Layout::validate_template("{project}").is_err());
+        assert!(Layout::validate_template("{projekt}/{repo}").is_err());
+        assert!(Layout::validate_template("../{repo}").is_err());
+    }
+
+    #[test]
+    fn test_collisions() {
+        let flat = layout("{project}-{repo}", "{project}-{repo}", false);
+        let repos = vec![repo("A-B", "", "c"), repo("A", "", "B-C")];
+        assert!(flat.check_collisions(&repos).is_err());
+        let nested = layout("{project}/{repo}", "{project}/{repo}", false);
+        assert!(nested.check_collisions(&repos).is_ok());
+    }
+}
@@ -1,13 +1,33 @@
 use futures::stream::{self, StreamExt};
-use generic_error::Result;
 use indicatif::{ProgressBar, ProgressStyle};
 
-use crate::bitbucket::types::Repo;
-use crate::git::single::SingleGit;
-use crate::types::GitOpts;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::bitbucket::types::{GitResult, Repo};
+use crate::error::{ErrorKind, Result};
+use crate::git::layout::Layout;
+use crate::git::report::{git_result, write_report};
+use crate::git::single::{Outcome, SingleGit};
+use crate::state::{RepoStatus, RunState};
+use crate::types::{Exit, GitOpts};
+use crate::util::{cancel, exec, handle_interrupts, interrupted, kill_running};
+
+pub mod layout;
+pub mod remote;
+mod report;
 mod single;
 
+/// How a run went, for the exit code.
+#[derive(Debug, Default)]
+pub struct RunTally {
+    pub repos: usize,
+    pub failed: usize,
+    pub interrupted: bool,
+}
+
 #[derive(Clone)]
 pub struct Git<'a, 'b> {
     repos: &'a [Repo],
@@ -19,49 +39,379 @@ impl Git<'_, '_> {
         Git { repos, opts }
     }
 
-    pub async fn git_going(self) {
-        if self.repos.is_empty() {
-            eprintln!("No repos to work on");
+    /// Existing directories that are not clones of their repo, with the reason why.
+    pub async fn broken_clones(&self) -> Vec<(String, String)> {
+        let opts_ref = &self.opts;
+        stream::iter(self.repos.iter().map(|repo| async move {
+            let git = SingleGit::new(repo, opts_ref);
+            if git.dir_exists() {
+                git.broken_reason().await.map(|reason| (git.path(), reason))
+            } else {
+                None
+            }
+        }))
+        .buffer_unordered(self.opts.concurrency)
+        .filter_map(|broken| async move { broken })
+        .collect()
+        .await
+    }
+
+    /// Full names of the repos without a clone that knows their default branch.
+    pub fn without_origin_head(&self) -> Vec<String> {
+        self.repos
+            .iter()
+            .filter(|repo| !SingleGit::new(repo, self.opts).has_origin_head())
+            .map(|repo| repo.full_name())
+            .collect()
+    }
+
+    /// Fetch the repos about to be cloned into the reference cache, one at a time,
+    /// so that forks only download what the cache doesn't already have.
+    async fn update_reference_cache(&self, cache: &str, stopping: &AtomicBool) {
+        let cache = Path::new(cache);
+        if !cache.join("HEAD").exists() {
+            let created = std::fs::create_dir_all(cache).is_ok()
+                && exec("git init --bare --quiet", cache)
+                    .await
+                    .map(|o| o.status.success())
+                    .unwrap_or(false);
+            if !created {
+                eprintln!("Unable to create reference cache {:?}", cache);
+                return;
+            }
+        }
+        let to_clone: Vec<SingleGit> = self
+            .repos
+            .iter()
+            .map(|repo| SingleGit::new(repo, self.opts))
+            .filter(|git| !git.dir_exists())
+            .collect();
+        if to_clone.is_empty() {
             return;
         }
-        let progress_bar: ProgressBar = ProgressBar::new(self.repos.len() as u64);
+        let progress_bar: ProgressBar = ProgressBar::new(to_clone.len() as u64);
         let bar_style = "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} (eta:{eta})";
         progress_bar.set_style(
             ProgressStyle::default_bar()
-                .template(&format!("Working repos {}", bar_style))
+                .template(&format!("Caching objects {}", bar_style))
                 .progress_chars("#>-"),
         );
-        let mut projects: Vec<&String> = self.repos.iter().map(|r| &r.project_key).collect();
-        projects.dedup();
-        projects.iter().for_each(|p| {
-            match std::fs::create_dir_all(format!("{}/{}", self.opts.output_directory, p)) {
+        for git in to_clone {
+            if stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            if let Err(e) = git.update_reference_cache(cache).await {
+                if !self.opts.quiet {
+                    progress_bar.println(e.msg);
+                }
+            }
+            progress_bar.inc(1);
+        }
+        progress_bar.finish();
+    }
+
+    fn create_parent_dirs(&self) {
+        let layout = Layout::new(self.opts);
+        let mut parents: Vec<PathBuf> = self
+            .repos
+            .iter()
+            .filter_map(|r| {
+                Path::new(&self.opts.output_directory)
+                    .join(layout.relative_path(r))
+                    .parent()
+                    .map(|p| p.to_path_buf())
+            })
+            .collect();
+        parents.sort();
+        parents.dedup();
+        parents
+            .iter()
+            .for_each(|p| match std::fs::create_dir_all(p) {
                 Ok(_) => {}
                 Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
                 Err(e) => {
-                    eprintln!("Unable to create project dir {} due to err: {:?}", p, e);
+                    eprintln!("Unable to create dir {:?} due to err: {:?}", p, e);
                     std::process::exit(1);
                 }
+            });
+    }
+
+    pub async fn git_going(self) -> RunTally {
+        if self.repos.is_empty() {
+            eprintln!("No repos to work on");
+            return RunTally::default();
+        }
+        let state = if self.opts.dry_run {
+            None
+        } else {
+            match RunState::open(&self.opts.output_directory) {
+                Ok(state) => Some(Arc::new(state)),
+                Err(e) => {
+                    eprintln!("Unable to record the outcome of this run. {}", e.msg);
+                    None
+                }
             }
-        });
-        let clone_result = stream::iter(self.repos.iter().map(|repo| {
+        };
+        let stopping = Arc::new(AtomicBool::new(false));
+        handle_interrupts(true);
+        let watcher = tokio::spawn(watch_interrupts(
+            stopping.clone(),
+            self.opts.grace_period_sec,
+            state.clone(),
+        ));
+        if let (Some(cache), false) = (&self.opts.reference_cache, self.opts.dry_run) {
+            self.update_reference_cache(cache, &stopping).await;
+        }
+        let progress_bar: ProgressBar = ProgressBar::new(self.repos.len() as u64);
+        let bar_style = "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} (eta:{eta})";
+        progress_bar.set_style(
+            ProgressStyle::default_bar()
+                .template(&format!("Working repos {}", bar_style))
+                .progress_chars("#>-"),
+        );
+        if !self.opts.dry_run {
+            self.create_parent_dirs();
+        }
+        let results = stream::iter(self.repos.iter().map(|repo| {
             let opts_ref = &self.opts;
             let progress_bar = progress_bar.clone();
+            let stopping = &stopping;
+            let state = &state;
             async move {
+                if stopping.load(Ordering::SeqCst) {
+                    if let Some(state) = state {
+                        state.record(repo, RepoStatus::Skipped, None);
+                    }
+                    return (repo, None, Duration::default());
+                }
+                let started = Instant::now();
                 let git = SingleGit::new(repo, opts_ref);
                 let result = git.clone_or_update().await;
                 progress_bar.inc(1);
-                result
+                if let Some(state) = state {
+                    let (status, error) = match &result {
+                        Ok(_) => (RepoStatus::Ok, None),
+                        Err(e) if e.kind == ErrorKind::Cancelled => {
+                            (RepoStatus::Skipped, Some(e.msg.clone()))
+                        }
+                        Err(e) => (RepoStatus::Failed, Some(e.msg.clone())),
+                    };
+                    state.record(repo, status, error);
+                }
+                (repo, Some(result), started.elapsed())
             }
         }))
         .buffer_unordered(self.opts.concurrency)
-        .collect::<Vec<Result<()>>>()
+        .collect::<Vec<(&Repo, Option<Result<Outcome>>, Duration)>>()
         .await;
+        watcher.abort();
+        handle_interrupts(false);
+        // The aborted watcher may still hold the state, and main exits before it is dropped.
+        if let Some(state) = &state {
+            state.dump();
+        }
 
         progress_bar.finish();
+        if self.opts.dry_run {
+            return print_plan(results);
+        }
+        if let Some(path) = &self.opts.report {
+            let mut report: Vec<GitResult> = results
+                .iter()
+                .map(|(repo, result, elapsed)| git_result(repo, result.as_ref(), *elapsed))
+                .collect();
+            report.sort_by(|a, b| (&a.project_key, &a.name).cmp(&(&b.project_key, &b.name)));
+            if let Err(e) = write_report(path, &report) {
+                eprintln!("Failed writing report {}. {}", path, e.msg);
+            }
+        }
+        let not_started = results.iter().filter(|(_, r, _)| r.is_none()).count();
+        let clone_result = results.into_iter().filter_map(|(_, result, _)| result);
         let mut failed: Vec<String> = vec![];
+        let mut cancelled: Vec<String> = vec![];
+        let mut timed_out: Vec<String> = vec![];
+        let (mut cloned, mut updated, mut unchanged) = (0, 0, 0);
+        let mut remote_changes: Vec<String> = vec![];
+        let mut default_branch_changes: Vec<String> = vec![];
+        let mut removed_branches: Vec<String> = vec![];
+        let mut backups: Vec<String> = vec![];
+        let mut reset_skipped: Vec<String> = vec![];
+        let mut extras_errors: Vec<String> = vec![];
+        let mut shared_bytes: u64 = 0;
+        let (mut retries, mut retried_repos) = (0, 0);
+        let mut updated_branches: usize = 0;
+        let mut diverged_branches: Vec<String> = vec![];
         for result in clone_result {
-            if let Err(e) = result {
-                failed.push(e.msg);
+            match result {
+                Ok(outcome) => {
+                    if outcome.cloned {
+                        cloned += 1;
+                    } else if outcome.unchanged {
+                        unchanged += 1;
+                    } else {
+                        updated += 1;
+                    }
+                    if let Some(change) = outcome.remote_change {
+                        remote_changes.push(format!("{}: {}", outcome.name, change));
+                    }
+                    if let Some(change) = outcome.default_branch_change {
+                        default_branch_changes.push(format!("{}: {}", outcome.name, change));
+                    }
+                    if !outcome.backups.is_empty() {
+                        backups.push(format!("{}: {}", outcome.name, outcome.backups.join(", ")));
+                    }
+                    if outcome.reset_skipped {
+                        reset_skipped.push(outcome.name.clone());
+                    }
+                    extras_errors.extend(outcome.extras_errors);
+                    shared_bytes += outcome.shared_bytes;
+                    if outcome.retries > 0 {
+                        retries += outcome.retries;
+                        retried_repos += 1;
+                    }
+                    updated_branches += outcome.updated_branches.len();
+                    for branch in outcome.diverged_branches {
+                        diverged_branches.push(format!("{}: {}", outcome.name, branch));
+                    }
+                    for branch in outcome.removed_branches {
+                        removed_branches.push(format!("{}: {}", outcome.name, branch));
+                    }
+                }
+                Err(e) if e.kind == ErrorKind::Timeout => timed_out.push(e.msg),
+                Err(e) if e.kind == ErrorKind::Cancelled => cancelled.push(e.msg),
+                Err(e) => failed.push(e.msg),
+            }
+        }
+
+        println!(
+            "\nCloned {} repos, updated {} repos, {} repos were unchanged.",
+            cloned, updated, unchanged
+        );
+
+        if retries > 0 {
+            println!(
+                "Retried git {} times in {} repos after network trouble.",
+                retries, retried_repos
+            );
+        }
+
+        if shared_bytes > 0 {
+            println!(
+                "Saved about {:.1} MiB of disk by sharing objects with the reference cache.",
+                shared_bytes as f64 / (1024.0 * 1024.0)
+            );
+        }
+
+        if !remote_changes.is_empty() {
+            remote_changes.sort();
+            println!("\nUpdated origin of {} repos.", remote_changes.len());
+            if !self.opts.quiet {
+                for change in remote_changes {
+                    println!("{}", change);
+                }
+            }
+        }
+
+        if !default_branch_changes.is_empty() {
+            default_branch_changes.sort();
+            println!(
+                "\nThe default branch changed in {} repos.",
+                default_branch_changes.len()
+            );
+            for change in default_branch_changes {
+                println!("{}", change);
+            }
+        }
+
+        if !backups.is_empty() {
+            backups.sort();
+            println!(
+                "\nSaved local work of {} repos before resetting.",
+                backups.len()
+            );
+            for backup in backups {
+                println!("{}", backup);
+            }
+        }
+
+        if !reset_skipped.is_empty() {
+            reset_skipped.sort();
+            println!(
+                "\nSkipped reset of {} repos with uncommitted or unpushed work.",
+                reset_skipped.len()
+            );
+            for name in reset_skipped {
+                println!("{}", name);
+            }
+        }
+
+        if updated_branches > 0 {
+            println!("\nFast-forwarded {} branches.", updated_branches);
+        }
+
+        if !diverged_branches.is_empty() {
+            diverged_branches.sort();
+            println!(
+                "\n{} branches have diverged from their upstream and were not updated.",
+                diverged_branches.len()
+            );
+            if !self.opts.quiet {
+                for branch in diverged_branches {
+                    println!("{}", branch);
+                }
+            }
+        }
+
+        if !removed_branches.is_empty() {
+            removed_branches.sort();
+            println!("\nRemoved {} merged branches.", removed_branches.len());
+            if !self.opts.quiet {
+                for branch in removed_branches {
+                    println!("{}", branch);
+                }
+            }
+        }
+
+        if !extras_errors.is_empty() {
+            eprintln!(
+                "\n{} LFS or submodule updates failed, the repos themselves are up to date.",
+                extras_errors.len()
+            );
+            if !self.opts.quiet {
+                for error in extras_errors {
+                    eprintln!("{}", error);
+                }
+            }
+        }
+
+        if not_started > 0 || !cancelled.is_empty() {
+            eprintln!(
+                "\nInterrupted, {} repos were not started and {} were cancelled.",
+                not_started,
+                cancelled.len()
+            );
+            if !self.opts.quiet {
+                for cancel in cancelled {
+                    eprintln!("{}", cancel);
+                }
+            }
+            eprintln!("Run again with --resume to continue where this run stopped.");
+        }
+
+        let tally = RunTally {
+            repos: self.repos.len(),
+            failed: failed.len() + timed_out.len(),
+            interrupted: stopping.load(Ordering::SeqCst),
+        };
+        if !timed_out.is_empty() {
+            eprintln!(
+                "\n{} projects timed out, git was killed after --git-timeout.",
+                timed_out.len()
+            );
+            if !self.opts.quiet {
+                for timeout in timed_out {
+                    eprintln!("{}", timeout);
+                }
             }
         }
 
@@ -73,5 +423,88 @@ impl Git<'_, '_> {
                 }
             }
         }
+
+        if tally.failed > 0 {
+            eprintln!("Run again with --retry-failed to retry only the failed repos.");
+        }
+        tally
+    }
+}
+
+/// Print what a --dry-run found to do, per repo.
+fn print_plan(results: Vec<(&Repo, Option<Result<Outcome>>, Duration)>) -> RunTally {
+    let mut tally = RunTally {
+        repos: results.len(),
+        interrupted: results.iter().any(|(_, r, _)| r.is_none()),
+        ..RunTally::default()
+    };
+    let mut outcomes: Vec<Outcome> = vec![];
+    let mut failed: Vec<String> = vec![];
+    for (_, result, _) in results {
+        match result {
+            Some(Ok(outcome)) => outcomes.push(outcome),
+            Some(Err(e)) if e.kind == ErrorKind::Cancelled => tally.interrupted = true,
+            Some(Err(e)) => failed.push(e.msg),
+            None => {}
+        }
+    }
+    outcomes.sort_by(|a, b| a.name.cmp(&b.name));
+    let (mut clones, mut updates) = (0, 0);
+    println!("\nDry run, nothing was changed.");
+    for outcome in outcomes.iter().filter(|o| !o.plan.is_empty()) {
+        if outcome.cloned {
+            clones += 1;
+        } else {
+            updates += 1;
+        }
+        println!("{}:", outcome.name);
+        for step in &outcome.plan {
+            println!("  {}", step);
+        }
+    }
+    println!(
+        "\nWould clone {} repos and update {} repos, {} repos need nothing.",
+        clones,
+        updates,
+        outcomes.len() - clones - updates
+    );
+    if !failed.is_empty() {
+        failed.sort();
+        eprintln!("\nUnable to plan {} repos.", failed.len());
+        for msg in &failed {
+            eprintln!("{}", msg);
+        }
+    }
+    tally.failed = failed.len();
+    tally
+}
+
+/// The first interrupt stops new repos from starting, and kills running git commands
+/// after the grace period. Interrupting again quits right away.
+async fn watch_interrupts(
+    stopping: Arc<AtomicBool>,
+    grace_period_sec: u64,
+    state: Option<Arc<RunState>>,
+) {
+    interrupted().await;
+    stopping.store(true, Ordering::SeqCst);
+    eprintln!(
+        "\nInterrupted, giving running repos {}s to finish. Interrupt again to quit now.",
+        grace_period_sec
+    );
+    tokio::select! {
+        _ = tokio::time::sleep(Duration::from_secs(grace_period_sec)) => cancel(),
+        _ = interrupted() => quit_now(state.as_deref()).await,
+    }
+    interrupted().await;
+    quit_now(state.as_deref()).await;
+}
+
+/// Exiting skips destructors, so the run state is dumped and running git commands are killed here.
+async fn quit_now(state: Option<&RunState>) {
+    if let Some(state) = state {
+        state.dump();
     }
+    kill_running().await;
+    std::process::exit(Exit::Interrupted.code())
 }
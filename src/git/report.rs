@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::bitbucket::types::{GitAction, GitResult, Repo};
+use crate::error::{ErrorKind, Result};
+use crate::git::single::Outcome;
+use crate::util::{fail, timestamp, unix_now};
+
+/// The report entry of a repo, where no result means the run never got to it.
+pub fn git_result(repo: &Repo, result: Option<&Result<Outcome>>, elapsed: Duration) -> GitResult {
+    let mut git_result = GitResult {
+        project_key: repo.project_key.clone(),
+        name: repo.name.clone(),
+        action: GitAction::Skipped,
+        old_head: None,
+        new_head: None,
+        duration_ms: elapsed.as_millis() as u64,
+        error_kind: None,
+        message: None,
+    };
+    match result {
+        None => git_result.message = Some("not started".to_owned()),
+        Some(Ok(outcome)) => {
+            git_result.action = if outcome.cloned {
+                GitAction::Cloned
+            } else if outcome.reset {
+                GitAction::Reset
+            } else if outcome.unchanged {
+                GitAction::Unchanged
+            } else {
+                GitAction::Updated
+            };
+            git_result.old_head = outcome.old_head.clone();
+            git_result.new_head = outcome.new_head.clone();
+        }
+        Some(Err(e)) => {
+            if e.kind != ErrorKind::Cancelled {
+                git_result.action = GitAction::Failed;
+            }
+            git_result.error_kind = Some(e.kind.name().to_owned());
+            git_result.message = Some(e.msg.clone());
+        }
+    }
+    git_result
+}
+
+/// Write the results as JUnit XML if the path ends with '.xml', and as JSON otherwise.
+pub fn write_report(path: &str, results: &[GitResult]) -> Result<()> {
+    let report = if path.to_lowercase().ends_with(".xml") {
+        junit(results)
+    } else {
+        json(results)?
+    };
+    std::fs::write(path, report)?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    timestamp: String,
+    repos: &'a [GitResult],
+}
+
+fn json(results: &[GitResult]) -> Result<String> {
+    let report = JsonReport {
+        timestamp: timestamp(unix_now()),
+        repos: results,
+    };
+    match serde_json::to_string_pretty(&report) {
+        Ok(json) => Ok(json),
+        Err(e) => fail(ErrorKind::Io, &format!("Failed serializing report. {}", e)),
+    }
+}
+
+/// One test suite per project and one test case per repo, so CI dashboards can show sync health.
+fn junit(results: &[GitResult]) -> String {
+    let mut projects: BTreeMap<&str, Vec<&GitResult>> = BTreeMap::new();
+    for result in results {
+        projects
+            .entry(&result.project_key)
+            .or_default()
+            .push(result);
+    }
+    let count = |results: &[&GitResult], action: GitAction| {
+        results.iter().filter(|r| r.action == action).count()
+    };
+    let seconds =
+        |results: &[&GitResult]| results.iter().map(|r| r.duration_ms).sum::<u64>() as f64 / 1000.0;
+    let all: Vec<&GitResult> = results.iter().collect();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites name=\"bitbucket_server_cli\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+        all.len(),
+        count(&all, GitAction::Failed),
+        count(&all, GitAction::Skipped),
+        seconds(&all)
+    ));
+    for (project, results) in projects {
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+            escape(project),
+            results.len(),
+            count(&results, GitAction::Failed),
+            count(&results, GitAction::Skipped),
+            seconds(&results)
+        ));
+        for result in results {
+            xml.push_str(&format!(
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\">\n",
+                escape(&result.project_key),
+                escape(&result.name),
+                result.duration_ms as f64 / 1000.0
+            ));
+            let message = escape(result.message.as_deref().unwrap_or(""));
+            match result.action {
+                GitAction::Failed => xml.push_str(&format!(
+                    "      <failure type=\"{}\" message=\"{}\"/>\n",
+                    escape(result.error_kind.as_deref().unwrap_or("failed")),
+                    message
+                )),
+                GitAction::Skipped => {
+                    xml.push_str(&format!("      <skipped message=\"{}\"/>\n", message))
+                }
+                action => xml.push_str(&format!(
+                    "      <system-out>{:?} {} -> {}</system-out>\n",
+                    action,
+                    result.old_head.as_deref().unwrap_or("none"),
+                    result.new_head.as_deref().unwrap_or("none")
+                )),
+            }
+            xml.push_str("    </testcase>\n");
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(project_key: &str, name: &str, action: GitAction) -> GitResult {
+        GitResult {
+            project_key: project_key.to_owned(),
+            name: name.to_owned(),
+            action,
+            old_head: None,
+            new_head: Some("1111111".to_owned()),
+            duration_ms: 1500,
+            error_kind: None,
+            message: None,
+        }
+    }
+
+    #[test]
+    fn test_junit() {
+        let mut failed = result("plat", "web", GitAction::Failed);
+        failed.error_kind = Some("timed_out".to_owned());
+        failed.message = Some("plat/web failed <fetch> & \"gave up\"".to_owned());
+        let xml = junit(&[
+            result("plat", "api", GitAction::Cloned),
+            failed,
+            result("~jensim", "dotfiles", GitAction::Skipped),
+        ]);
+        assert!(xml.contains(
+            "<testsuites name=\"bitbucket_server_cli\" tests=\"3\" failures=\"1\" skipped=\"1\" time=\"4.500\">"
+        ));
+        assert!(xml.contains(
+            "<testsuite name=\"plat\" tests=\"2\" failures=\"1\" skipped=\"0\" time=\"3.000\">"
+        ));
+        assert!(xml.contains("<system-out>Cloned none -> 1111111</system-out>"));
+        assert!(xml.contains(
+            "<failure type=\"timed_out\" message=\"plat/web failed &lt;fetch&gt; &amp; &quot;gave up&quot;\"/>"
+        ));
+        assert!(xml.contains("<testcase classname=\"~jensim\" name=\"dotfiles\" time=\"1.500\">"));
+    }
+
+    #[test]
+    fn test_json() {
+        let json = json(&[result("plat", "api", GitAction::Unchanged)]).unwrap();
+        assert!(json.contains("\"action\": \"unchanged\""));
+        assert!(json.contains("\"new_head\": \"1111111\""));
+        assert!(json.contains("\"duration_ms\": 1500"));
+    }
+}
@@ -1,162 +1,967 @@
 use crate::bitbucket::types::Repo;
-use crate::types::GitOpts;
-use crate::util::{bail, exec};
-use generic_error::Result;
+use crate::error::{ErrorKind, GitFailure, Result};
+use crate::filter::glob_match;
+use crate::git::layout::Layout;
+use crate::git::remote::{
+    credential_rewrite, mask_credentials, parse_ls_remote, repo_identity, same_repo,
+};
+use crate::types::{GitOpts, PartialSettings};
+use crate::util::{exec_timeout, fail, timestamp, unix_now};
+use std::collections::BTreeMap;
 use std::path::Path;
-use std::process::Output;
+use std::process::{ExitStatus, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 
-#[derive(Clone)]
 pub struct SingleGit<'a, 'b> {
     repo: &'a Repo,
     opts: &'b GitOpts,
+    retries: AtomicUsize,
+    plan: Mutex<Vec<String>>,
+}
+
+/// What happened to one repo, for the summary after a run.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub name: String,
+    pub cloned: bool,
+    pub unchanged: bool,
+    pub reset: bool,
+    pub old_head: Option<String>,
+    pub new_head: Option<String>,
+    pub remote_change: Option<String>,
+    pub default_branch_change: Option<String>,
+    pub removed_branches: Vec<String>,
+    pub backups: Vec<String>,
+    pub reset_skipped: bool,
+    pub updated_branches: Vec<String>,
+    pub diverged_branches: Vec<String>,
+    pub extras_errors: Vec<String>,
+    pub shared_bytes: u64,
+    pub retries: usize,
+    /// The changes a --dry-run would have made, in order.
+    pub plan: Vec<String>,
+}
+
+/// Work in a clone that a reset would throw away.
+struct LocalWork {
+    uncommitted: bool,
+    unpushed: bool,
+}
+
+/// A local branch that is merged into the main branch.
+struct MergedBranch {
+    name: String,
+    has_upstream: bool,
+    created: u64,
 }
 
 impl SingleGit<'_, '_> {
     pub fn new<'a, 'b>(repo: &'a Repo, opts: &'b GitOpts) -> SingleGit<'a, 'b> {
-        SingleGit { repo, opts }
+        SingleGit {
+            repo,
+            opts,
+            retries: AtomicUsize::new(0),
+            plan: Mutex::new(vec![]),
+        }
     }
 
-    pub async fn clone_or_update(&self) -> Result<()> {
+    pub async fn clone_or_update(&self) -> Result<Outcome> {
+        let mut outcome = Outcome {
+            name: self.repo.full_name(),
+            ..Outcome::default()
+        };
         if self.dir_exists() {
-            if self.opts.reset_state {
-                self.git_reset().await?;
+            match self.broken_reason().await {
+                None => {
+                    outcome.old_head = self.head().await;
+                    if !self.opts.keep_remotes {
+                        outcome.remote_change = self.sync_remote().await?;
+                    }
+                    // Only reads, so a dry run plans no update for what would be left alone.
+                    let remote = self.ls_remote().await;
+                    outcome.unchanged = !self.opts.always_fetch
+                        && match &remote {
+                            Some((head, heads)) => self.is_unchanged(head, heads.clone()).await,
+                            None => false,
+                        };
+                    if !outcome.unchanged {
+                        let remote_head = remote.and_then(|(head, _)| head);
+                        outcome.default_branch_change =
+                            self.follow_default_branch(remote_head).await?;
+                    }
+                    if self.opts.reset_state {
+                        let local_work = self.local_work().await?;
+                        if self.opts.reset_skip_unpushed
+                            && (local_work.uncommitted || local_work.unpushed)
+                        {
+                            outcome.reset_skipped = true;
+                        } else {
+                            outcome.backups = self.back_up(&local_work).await?;
+                            self.git_reset().await?;
+                            outcome.reset = true;
+                        }
+                    }
+                    if !outcome.unchanged {
+                        outcome.removed_branches = self.git_update().await?;
+                    }
+                    // Also when unchanged, to retry extras that failed before.
+                    // Both only go to the server for what is missing.
+                    outcome.extras_errors = self.update_extras().await;
+                    if self.opts.update_all_branches && !outcome.unchanged {
+                        let (updated, diverged) = self.fast_forward_branches().await?;
+                        outcome.updated_branches = updated;
+                        outcome.diverged_branches = diverged;
+                    }
+                }
+                Some(reason) if self.opts.reclone_broken => {
+                    self.move_aside(&reason)?;
+                    self.git_clone_and_set_up(&mut outcome).await?;
+                }
+                Some(reason) => self.generate_repo_err(
+                    ErrorKind::Git(GitFailure::Broken),
+                    "update",
+                    &format!("{}, re-clone it with --reclone-broken", reason),
+                )?,
             }
-            self.git_update().await?;
         } else {
-            self.git_clone().await?;
+            self.git_clone_and_set_up(&mut outcome).await?;
+        }
+        outcome.new_head = self.head().await;
+        outcome.retries = self.retries.load(Ordering::Relaxed);
+        outcome.plan = std::mem::take(&mut *self.plan.lock().unwrap());
+        Ok(outcome)
+    }
+
+    async fn git_clone_and_set_up(&self, outcome: &mut Outcome) -> Result<()> {
+        self.git_clone().await?;
+        outcome.cloned = true;
+        // There is nothing to look at in a clone that was only planned.
+        if !self.opts.dry_run {
+            self.sync_remote().await?;
+            outcome.extras_errors = self.update_extras().await;
+            outcome.shared_bytes = self.shared_bytes().await;
         }
         Ok(())
     }
 
-    async fn git_clone(&self) -> Result<()> {
-        let string_path = format!("{}/{}", self.opts.output_directory, self.repo.project_key);
+    /// Point origin at the current clone url, and push url if there is one.
+    /// Returns a description of what was changed.
+    async fn sync_remote(&self) -> Result<Option<String>> {
+        let string_path = self.path();
         let path = Path::new(&string_path);
-
-        let extra_conf = if self.opts.ssl_allow_anything {
-            "-c http.sslVerify=false "
+        let mut changes: Vec<String> = vec![];
+        let origin = self.git_config("remote.origin.url").await;
+        if origin.as_deref() != Some(self.repo.git.as_str()) {
+            self.act(
+                "updating origin url",
+                &format!("git remote set-url origin {}", self.repo.git),
+                path,
+            )
+            .await?;
+            changes.push(format!(
+                "{} -> {}",
+                mask_credentials(&origin.unwrap_or_default()),
+                mask_credentials(&self.repo.git)
+            ));
+        }
+        if let Some(push_url) = &self.repo.push_url {
+            if self.git_config("remote.origin.pushurl").await.as_ref() != Some(push_url) {
+                self.act(
+                    "updating origin push url",
+                    &format!("git remote set-url --push origin {}", push_url),
+                    path,
+                )
+                .await?;
+                changes.push(format!("push {}", mask_credentials(push_url)));
+            }
+        }
+        Ok(if changes.is_empty() {
+            None
         } else {
-            ""
+            Some(changes.join(", "))
+        })
+    }
+
+    /// The branch HEAD points at on the server and the server's branch heads.
+    async fn ls_remote(&self) -> Option<(Option<String>, BTreeMap<String, String>)> {
+        match self
+            .exec(
+                "git ls-remote --symref origin HEAD \"refs/heads/*\"",
+                &self.path(),
+            )
+            .await
+        {
+            Ok(o) if o.status.success() => {
+                Some(parse_ls_remote(&String::from_utf8_lossy(&o.stdout)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the remote has the same HEAD and branch heads as were fetched last time,
+    /// and the main branch is checked out and up to date, so there is nothing to fetch.
+    async fn is_unchanged(
+        &self,
+        remote_head: &Option<String>,
+        mut remote_heads: BTreeMap<String, String>,
+    ) -> bool {
+        let string_path = self.path();
+        let path = Path::new(&string_path);
+        let main_branch = match remote_head {
+            Some(main_branch) if self.origin_head().await.as_ref() == Some(main_branch) => {
+                main_branch.clone()
+            }
+            _ => return false,
+        };
+        if self.partial().is_some_and(|p| p.single_branch) {
+            remote_heads.retain(|branch, _| branch == &main_branch);
+        }
+        let tracking = match self.exec(
+            "git for-each-ref --format=\"%(objectname) %(refname:strip=3)\" refs/remotes/origin",
+            path,
+        )
+        .await
+        {
+            Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout).into_owned(),
+            _ => return false,
+        };
+        let local_heads: BTreeMap<String, String> = tracking
+            .lines()
+            .filter_map(|l| {
+                let mut parts = l.splitn(2, ' ');
+                match (parts.next(), parts.next()) {
+                    (Some(_), Some("HEAD")) => None,
+                    (Some(sha), Some(branch)) => Some((branch.to_owned(), sha.to_owned())),
+                    _ => None,
+                }
+            })
+            .collect();
+        let head = self
+            .exec(
+                "git rev-parse --abbrev-ref HEAD && git rev-parse HEAD",
+                path,
+            )
+            .await
+            .map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+            .unwrap_or_default();
+        let mut head = head.lines();
+        local_heads == remote_heads
+            && head.next() == Some(main_branch.as_str())
+            && head.next() == remote_heads.get(&main_branch).map(|s| s.as_str())
+    }
+
+    /// Point origin/HEAD at the server's HEAD as seen by ls-remote, and rename the local
+    /// branch along with it if asked to. Returns a description of the change of default branch.
+    async fn follow_default_branch(&self, remote_head: Option<String>) -> Result<Option<String>> {
+        let string_path = self.path();
+        let path = Path::new(&string_path);
+        let old = self.origin_head().await;
+        let new = match remote_head {
+            Some(new) if old.as_ref() != Some(&new) => new,
+            _ => return Ok(None),
         };
-        self.exec_resolve(
-            &format!("git clone into {}", self.opts.output_directory),
+        // The new default branch may not have been fetched yet.
+        self.act(
+            "refresh origin HEAD",
             &format!(
-                "git {}clone {} {}",
-                extra_conf, self.repo.git, self.repo.name
+                "git fetch --quiet origin +refs/heads/{}:refs/remotes/origin/{} && git remote set-head origin {}",
+                new, new, new
             ),
             path,
         )
         .await?;
-        Ok(())
+        let old = match old {
+            Some(old) => old,
+            None => return Ok(None),
+        };
+        let mut change = format!("{} -> {}", old, new);
+        if self.opts.rename_default_branch
+            && self.local_branch_exists(&old).await
+            && !self.local_branch_exists(&new).await
+        {
+            for cmd in &[
+                format!("git branch -m {} {}", old, new),
+                format!("git branch --set-upstream-to=origin/{} {}", new, new),
+            ] {
+                self.act("rename default branch", cmd, path).await?;
+            }
+            change.push_str(", renamed local branch");
+        }
+        Ok(Some(change))
+    }
+
+    /// The branch origin/HEAD points at, as last fetched.
+    async fn origin_head(&self) -> Option<String> {
+        match self
+            .exec(
+                "git symbolic-ref --short refs/remotes/origin/HEAD",
+                &self.path(),
+            )
+            .await
+        {
+            Ok(o) if o.status.success() => {
+                let head = String::from_utf8_lossy(&o.stdout).trim().to_owned();
+                Some(head.trim_start_matches("origin/").to_owned())
+            }
+            _ => None,
+        }
+    }
+
+    async fn local_branch_exists(&self, branch: &str) -> bool {
+        let cmd = format!("git show-ref --verify --quiet refs/heads/{}", branch);
+        self.exec(&cmd, &self.path())
+            .await
+            .map(|o| o.status.success())
+            .unwrap_or(false)
+    }
+
+    async fn head(&self) -> Option<String> {
+        match self
+            .exec("git rev-parse --verify --quiet HEAD", &self.path())
+            .await
+        {
+            Ok(o) if o.status.success() => {
+                Some(String::from_utf8_lossy(&o.stdout).trim().to_owned())
+            }
+            _ => None,
+        }
+    }
+
+    async fn git_config(&self, key: &str) -> Option<String> {
+        match self
+            .exec(&format!("git config --get {}", key), &self.path())
+            .await
+        {
+            Ok(o) if o.status.success() => {
+                Some(String::from_utf8_lossy(&o.stdout).trim().to_owned())
+            }
+            _ => None,
+        }
+    }
+
+    /// Why an existing directory can not be updated, if it is not a clone of the repo.
+    pub async fn broken_reason(&self) -> Option<String> {
+        let string_path = self.path();
+        let path = Path::new(&string_path);
+        let top_level = match self.exec("git rev-parse --show-toplevel", path).await {
+            Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout).trim().to_owned(),
+            Ok(_) => return Some("not a git repository".to_owned()),
+            // Not knowing is no reason to move the directory aside, updating will tell.
+            Err(_) => return None,
+        };
+        if Path::new(&top_level).canonicalize().ok() != path.canonicalize().ok() {
+            return Some("not a git repository".to_owned());
+        }
+        match self.git_config("remote.origin.url").await {
+            Some(origin) if same_repo(&origin, &self.repo.git) => None,
+            Some(origin) => Some(format!(
+                "origin points at {}",
+                repo_identity(&origin).unwrap_or(origin)
+            )),
+            None => Some("no origin remote".to_owned()),
+        }
+    }
+
+    /// Keep broken directories for inspection, but out of the way. Empty ones are just removed.
+    fn move_aside(&self, reason: &str) -> Result<()> {
+        let string_path = self.path();
+        let path = Path::new(&string_path);
+        let is_empty = std::fs::read_dir(path)
+            .map(|mut d| d.next().is_none())
+            .unwrap_or(false);
+        let moved = if is_empty {
+            self.act_fs(&format!("rmdir {}", string_path), || {
+                std::fs::remove_dir(path)
+            })
+        } else {
+            let aside = format!("{}.broken-{}", string_path, unix_now());
+            self.act_fs(&format!("mv {} {}", string_path, aside), || {
+                eprintln!(
+                    "{} was not a clone of {} ({}), moved it to {}",
+                    string_path,
+                    self.repo.full_name(),
+                    reason,
+                    aside
+                );
+                std::fs::rename(path, &aside)
+            })
+        };
+        match moved {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.generate_repo_err(ErrorKind::Io, "moving broken clone aside", &e.to_string())
+            }
+        }
+    }
+
+    /// Clone into a temporary sibling directory, and move it into place only when complete,
+    /// so that an interrupted clone never looks like an existing repo.
+    async fn git_clone(&self) -> Result<()> {
+        let path = Path::new(&self.opts.output_directory);
+        let relative_path = self.layout().relative_path(self.repo);
+        let tmp_relative_path = tmp_clone_path(&relative_path);
+        let tmp_path = path.join(&tmp_relative_path);
+        if tmp_path.exists() {
+            if let Err(e) = self.act_fs(&format!("rm -r {}", tmp_path.display()), || {
+                std::fs::remove_dir_all(&tmp_path)
+            }) {
+                self.generate_repo_err(
+                    ErrorKind::Io,
+                    "removing stale temporary clone",
+                    &e.to_string(),
+                )?;
+            }
+        }
+
+        let extra_conf = if self.opts.ssl_allow_anything {
+            "-c http.sslVerify=false "
+        } else {
+            ""
+        };
+        let mut cloned = self
+            .act(
+                &format!("git clone into {}", self.opts.output_directory),
+                &format!(
+                    "git {}clone{}{} {} \"{}\"",
+                    extra_conf,
+                    self.partial_clone_args(),
+                    self.reference_args(),
+                    self.repo.git,
+                    tmp_relative_path
+                ),
+                path,
+            )
+            .await;
+        let sparse = self.partial().map(|p| p.sparse).unwrap_or_default();
+        if cloned.is_ok() && !sparse.is_empty() {
+            let patterns: Vec<String> = sparse.iter().map(|p| format!("\"{}\"", p)).collect();
+            cloned = self
+                .act(
+                    "sparse checkout",
+                    &format!("git sparse-checkout set {}", patterns.join(" ")),
+                    &tmp_path,
+                )
+                .await;
+        }
+        let path_string = self.path();
+        let moved = cloned.and_then(|_| {
+            let description = format!("mv {} {}", tmp_path.display(), path_string);
+            match self.act_fs(&description, || std::fs::rename(&tmp_path, &path_string)) {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    self.generate_repo_err(ErrorKind::Io, "moving clone into place", &e.to_string())
+                }
+            }
+        });
+        if moved.is_err() && tmp_path.exists() {
+            std::fs::remove_dir_all(&tmp_path).unwrap_or(());
+        }
+        moved
     }
 
-    async fn git_update(&self) -> Result<()> {
+    async fn git_update(&self) -> Result<Vec<String>> {
         let string_path = self.path();
         let path = Path::new(&string_path);
-        let current_branch_raw: Vec<u8> =
-            exec("git rev-parse --abbrev-ref HEAD", path).await?.stdout;
+        let current_branch_raw: Vec<u8> = self
+            .exec("git rev-parse --abbrev-ref HEAD", path)
+            .await?
+            .stdout;
         let current_branch: &str = std::str::from_utf8(&current_branch_raw)?.trim();
         let main_branch: String = self.get_git_main().await?;
 
-        if main_branch == "(unknown)" {
-            self.generate_repo_err("get main branch", "main branch unknown")?;
-        } else if current_branch == main_branch.as_str() {
-            self.exec_resolve("git pull", "git pull --autostash --ff-only --rebase", path)
-                .await?;
+        if current_branch == main_branch.as_str() {
+            self.act(
+                "git pull",
+                "git pull --autostash --ff-only --rebase --prune",
+                path,
+            )
+            .await?;
         } else {
-            self.exec_resolve(
+            self.act(
                 "git fetch",
                 &format!("git fetch origin {}:{}", main_branch, main_branch),
                 path,
             )
             .await?;
             if self
-                .exec_resolve("git pull", "git pull --autostash --ff-only --rebase", path)
+                .act(
+                    "git pull",
+                    "git pull --autostash --ff-only --rebase --prune",
+                    path,
+                )
                 .await
                 .is_err()
             {
                 // Do nothing
             }
-            self.exec_resolve(
+            self.act(
                 &format!("checkout {}", main_branch),
                 &format!("git checkout {}", main_branch),
                 path,
             )
             .await?;
         }
-        self.clean_merged_branches(&main_branch, path).await?;
-        Ok(())
+        if self.opts.clean_merged {
+            self.clean_merged_branches(&main_branch, path).await
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    /// Fetch LFS files and submodules when asked for and used by the repo.
+    /// Failures are returned for the summary, instead of failing the repo.
+    async fn update_extras(&self) -> Vec<String> {
+        let string_path = self.path();
+        let path = Path::new(&string_path);
+        let mut errors: Vec<String> = vec![];
+        // Lend the saved login to submodules on the same server.
+        let conf = match credential_rewrite(&self.repo.git) {
+            Some((with_login, without_login)) => {
+                format!("-c url.\"{}\".insteadOf=\"{}\" ", with_login, without_login)
+            }
+            None => String::new(),
+        };
+        let conf = if self.opts.ssl_allow_anything {
+            format!("{}-c http.sslVerify=false ", conf)
+        } else {
+            conf
+        };
+        if self.opts.submodules
+            && path.join(".gitmodules").exists()
+            && self
+                .extra_needed("git submodule status --recursive", submodules_missing, path)
+                .await
+        {
+            let cmd = format!(
+                "git {c}submodule sync --quiet --recursive && git {c}submodule update --init --recursive --jobs {}",
+                self.opts.submodule_jobs,
+                c = conf
+            );
+            if let Err(e) = self.act("submodule update", &cmd, path).await {
+                errors.push(e.msg);
+            }
+        }
+        let attributes = std::fs::read_to_string(path.join(".gitattributes")).unwrap_or_default();
+        if self.opts.lfs
+            && uses_lfs(&attributes)
+            && self
+                .extra_needed("git lfs ls-files", lfs_missing, path)
+                .await
+        {
+            let cmd = format!(
+                "git {}-c lfs.concurrenttransfers={} lfs pull",
+                conf, self.opts.lfs_transfers
+            );
+            if let Err(e) = self.act("lfs pull", &cmd, path).await {
+                errors.push(e.msg);
+            }
+        }
+        errors
+    }
+
+    /// Whether to plan an extra in a dry run of a repo that has nothing else planned.
+    /// Only when something is missing, so that repos needing nothing aren't counted as updates.
+    async fn extra_needed(&self, check: &str, missing: fn(&str) -> bool, path: &Path) -> bool {
+        if !self.opts.dry_run || !self.plan.lock().unwrap().is_empty() {
+            return true;
+        }
+        match self.exec(check, path).await {
+            Ok(o) if o.status.success() => missing(&String::from_utf8_lossy(&o.stdout)),
+            _ => true,
+        }
+    }
+
+    /// The --depth, --filter, --single-branch and --sparse settings for this repo, if any.
+    fn partial(&self) -> Option<PartialSettings> {
+        self.opts.partial_settings(self.repo)
+    }
+
+    fn partial_clone_args(&self) -> String {
+        let partial = match self.partial() {
+            Some(partial) => partial,
+            None => return String::new(),
+        };
+        let mut args = String::new();
+        if let Some(depth) = partial.depth {
+            args.push_str(&format!(" --depth {}", depth));
+        }
+        if let Some(filter) = &partial.filter {
+            args.push_str(&format!(" --filter={}", filter));
+        }
+        if partial.single_branch {
+            args.push_str(" --single-branch");
+        } else if partial.depth.is_some() {
+            // --depth implies --single-branch unless told otherwise
+            args.push_str(" --no-single-branch");
+        }
+        if !partial.sparse.is_empty() {
+            args.push_str(" --sparse");
+        }
+        args
+    }
+
+    /// Fetch the branches of the repo into the shared reference cache, namespaced per repo.
+    pub async fn update_reference_cache(&self, cache: &Path) -> Result<()> {
+        let extra_conf = if self.opts.ssl_allow_anything {
+            "-c http.sslVerify=false "
+        } else {
+            ""
+        };
+        let cmd = format!(
+            "git {}fetch --quiet --no-tags {} \"+refs/heads/*:refs/{}/*\"",
+            extra_conf,
+            self.repo.git,
+            cache_namespace(self.repo)
+        );
+        self.act("updating reference cache", &cmd, cache)
+            .await
+            .map(|_| ())
+    }
+
+    fn reference_args(&self) -> String {
+        match (&self.opts.reference_cache, self.opts.dissociate) {
+            (Some(cache), false) => format!(" --reference-if-able \"{}\"", cache),
+            (Some(cache), true) => format!(" --reference-if-able \"{}\" --dissociate", cache),
+            (None, _) => String::new(),
+        }
+    }
+
+    /// Bytes of objects the clone reads from the reference cache instead of storing them.
+    async fn shared_bytes(&self) -> u64 {
+        if self.opts.reference_cache.is_none() || self.opts.dissociate {
+            return 0;
+        }
+        let string_path = self.path();
+        let path = Path::new(&string_path);
+        let total: u64 = match self
+            .exec("git rev-list --all --objects --disk-usage", path)
+            .await
+        {
+            Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout)
+                .trim()
+                .parse()
+                .unwrap_or(0),
+            _ => return 0,
+        };
+        let own_kib: u64 = match self.exec("git count-objects -v", path).await {
+            Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout)
+                .lines()
+                .filter_map(|l| {
+                    l.strip_prefix("size: ")
+                        .or_else(|| l.strip_prefix("size-pack: "))
+                })
+                .filter_map(|kib| kib.trim().parse::<u64>().ok())
+                .sum(),
+            _ => return 0,
+        };
+        total.saturating_sub(own_kib * 1024)
+    }
+
+    /// Fast-forward local branches that are behind their upstream.
+    /// Returns the updated branches, and those that have diverged and were left alone.
+    async fn fast_forward_branches(&self) -> Result<(Vec<String>, Vec<String>)> {
+        let string_path = self.path();
+        let path = Path::new(&string_path);
+        // The update has just fetched, so the tracking branches are current.
+        let current_raw: Vec<u8> = self
+            .exec("git rev-parse --abbrev-ref HEAD", path)
+            .await?
+            .stdout;
+        let current_branch = String::from_utf8_lossy(&current_raw).trim().to_owned();
+        let out_raw: Vec<u8> = self
+            .exec_resolve(
+                "list tracking branches",
+                "git for-each-ref --format=\"%(refname:short) %(upstream:short) %(upstream:track)\" refs/heads",
+                path,
+            )
+            .await?
+            .stdout;
+        let out = String::from_utf8_lossy(&out_raw);
+        let mut updated: Vec<String> = vec![];
+        let mut diverged: Vec<String> = vec![];
+        for line in out.lines() {
+            let mut parts = line.splitn(3, ' ');
+            let (branch, upstream, track) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(b), Some(u), Some(t)) if !u.is_empty() => (b, u, t),
+                _ => continue,
+            };
+            if track.contains("ahead") && track.contains("behind") {
+                diverged.push(branch.to_owned());
+            } else if track.contains("behind") {
+                if !self.is_ancestor(branch, upstream, path).await? {
+                    diverged.push(branch.to_owned());
+                    continue;
+                }
+                let cmd = if branch == current_branch {
+                    format!("git merge --ff-only --quiet {}", upstream)
+                } else {
+                    format!("git fetch --quiet . {}:{}", upstream, branch)
+                };
+                self.act("fast-forward", &cmd, path).await?;
+                updated.push(branch.to_owned());
+            }
+        }
+        Ok((updated, diverged))
+    }
+
+    async fn is_ancestor(&self, ancestor: &str, descendant: &str, path: &Path) -> Result<bool> {
+        let cmd = format!("git merge-base --is-ancestor {} {}", ancestor, descendant);
+        let o = self.exec(&cmd, path).await?;
+        match o.status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => self.generate_repo_err_from_output("merge-base", o.stdout, o.stderr),
+        }
     }
 
-    async fn clean_merged_branches(&self, main_branch: &str, path: &Path) -> Result<()> {
+    /// Delete merged branches that have been pushed, are old enough and are not protected.
+    /// Returns the names of the removed branches.
+    async fn clean_merged_branches(&self, main_branch: &str, path: &Path) -> Result<Vec<String>> {
         let out_raw: Vec<u8> = self
-            .exec_resolve("list merged branches", "git branch --merged", path)
+            .exec_resolve(
+                "list merged branches",
+                &format!(
+                    "git for-each-ref --merged={} --format=\"%(refname:short) %(upstream)\" refs/heads",
+                    main_branch
+                ),
+                path,
+            )
             .await?
             .stdout;
         let out: &str = std::str::from_utf8(&out_raw)?;
+        let now = unix_now();
+        let mut removed: Vec<String> = vec![];
         for line in out.lines() {
-            if !line.starts_with("* ") && line != format!("  {}", main_branch) {
-                self.exec_resolve(
-                    "remove branch",
-                    &format!("git branch -D {}", line.trim()),
-                    path,
-                )
-                .await?;
+            let mut parts = line.split_whitespace();
+            let name = match parts.next() {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            let branch = MergedBranch {
+                has_upstream: parts.next().is_some(),
+                created: self.branch_created(&name, path).await,
+                name,
+            };
+            if self.is_removable(&branch, main_branch, now)
+                && self
+                    .act(
+                        "delete merged branch",
+                        &format!("git branch -d {}", branch.name),
+                        path,
+                    )
+                    .await
+                    .is_ok()
+            {
+                removed.push(branch.name);
             }
         }
-        Ok(())
+        Ok(removed)
     }
 
+    /// When the branch was created according to its reflog, or else its last commit.
+    async fn branch_created(&self, branch: &str, path: &Path) -> u64 {
+        // The oldest reflog entry is last, and reads like 'refs/heads/branch@{1700000000}'.
+        let reflog = format!(
+            "git reflog show --date=unix --format=%gd refs/heads/{}",
+            branch
+        );
+        if let Ok(o) = self.exec(&reflog, path).await {
+            let out = String::from_utf8_lossy(&o.stdout);
+            let created = out
+                .lines()
+                .last()
+                .and_then(|l| l.trim().rsplit("@{").next())
+                .and_then(|t| t.strip_suffix('}'))
+                .and_then(|t| t.parse::<u64>().ok());
+            if let Some(created) = created {
+                return created;
+            }
+        }
+        let last_commit = format!("git log -1 --format=%ct refs/heads/{}", branch);
+        if let Ok(o) = self.exec(&last_commit, path).await {
+            if let Ok(created) = String::from_utf8_lossy(&o.stdout).trim().parse::<u64>() {
+                return created;
+            }
+        }
+        u64::MAX
+    }
+
+    fn is_removable(&self, branch: &MergedBranch, main_branch: &str, now: u64) -> bool {
+        let min_age = self.opts.clean_min_age_days * 24 * 60 * 60;
+        branch.name != main_branch
+            && branch.has_upstream
+            && branch.created.saturating_add(min_age) <= now
+            && !self
+                .opts
+                .protect_branches
+                .iter()
+                .any(|p| glob_match(p, &branch.name))
+    }
+
+    async fn exec<P: AsRef<Path>>(&self, cmd: &str, path: P) -> Result<Output> {
+        exec_timeout(cmd, path, self.opts.git_timeout.map(Duration::from_secs)).await
+    }
+
+    /// Run a command that has to succeed, retrying failures that look like network trouble.
     async fn exec_resolve(&self, action: &str, cmd: &str, path: &Path) -> Result<Output> {
-        match exec(cmd, path).await {
-            Ok(o) if o.status.success() => Ok(o),
-            Ok(o) if !o.status.success() => {
-                self.generate_repo_err_from_output(action, o.stdout, o.stderr)
+        let mut attempt: u32 = 0;
+        loop {
+            match self.exec(cmd, path).await {
+                Ok(o) if o.status.success() => return Ok(o),
+                Ok(o)
+                    if attempt < self.opts.git_retries
+                        && git_failure(&String::from_utf8_lossy(&o.stderr))
+                            == GitFailure::Network =>
+                {
+                    attempt += 1;
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                    let backoff = self.opts.git_backoff_sec * u64::from(attempt);
+                    tokio::time::sleep(Duration::from_secs(backoff)).await;
+                }
+                Ok(o) => return self.generate_repo_err_from_output(action, o.stdout, o.stderr),
+                Err(e) => return self.generate_repo_err(e.kind, action, &e.msg),
             }
-            Err(e) => self.generate_repo_err(action, &e.msg),
-            _ => self.generate_repo_err(action, "unknown"),
         }
     }
 
+    /// Run a command that changes the clone, or with --dry-run only add it to the plan.
+    /// Every change goes through here, so a dry run plans exactly what a run would do.
+    async fn act(&self, action: &str, cmd: &str, path: &Path) -> Result<Output> {
+        if self.opts.dry_run {
+            let masked: Vec<String> = cmd.split(' ').map(mask_credentials).collect();
+            self.plan.lock().unwrap().push(masked.join(" "));
+            return Ok(Output {
+                status: ExitStatus::default(),
+                stdout: vec![],
+                stderr: vec![],
+            });
+        }
+        self.exec_resolve(action, cmd, path).await
+    }
+
+    /// Like act, without checking the exit status or retrying.
+    async fn act_unchecked(&self, action: &str, cmd: &str, path: &Path) -> Result<Output> {
+        if self.opts.dry_run {
+            return self.act(action, cmd, path).await;
+        }
+        self.exec(cmd, path).await
+    }
+
+    /// Like act, for changes to the file system.
+    fn act_fs<F>(&self, description: &str, change: F) -> std::io::Result<()>
+    where
+        F: FnOnce() -> std::io::Result<()>,
+    {
+        if self.opts.dry_run {
+            self.plan.lock().unwrap().push(description.to_owned());
+            Ok(())
+        } else {
+            change()
+        }
+    }
+
+    /// The main branch as reported by the server during inventory, or else origin/HEAD,
+    /// and only as a last resort asking the remote.
     async fn get_git_main(&self) -> Result<String> {
+        if let Some(default_branch) = &self.repo.default_branch {
+            return Ok(default_branch.clone());
+        }
+        if let Some(origin_head) = self.origin_head().await {
+            return Ok(origin_head);
+        }
         let path_string = self.path();
         let path = Path::new(&path_string);
         let raw: Vec<u8> = self
-            .exec_resolve("git remote show origin", "git remote show origin", path)
+            .exec_resolve("git ls-remote", "git ls-remote --symref origin HEAD", path)
             .await?
             .stdout;
-        let stdout: &str = std::str::from_utf8(&raw)?;
-        self.get_git_main_from_remote_info(stdout)
+        match main_from_symref(&String::from_utf8_lossy(&raw)) {
+            Some(main_branch) => Ok(main_branch),
+            None => self.generate_repo_err(
+                ErrorKind::Git(GitFailure::Other),
+                "get main branch",
+                "main branch unknown",
+            ),
+        }
     }
 
-    fn get_git_main_from_remote_info(&self, remote_info: &str) -> Result<String> {
-        let opt: Option<String> = remote_info
-            .lines()
-            .find(|s| s.starts_with("  HEAD branch: "))
-            .map(|s| String::from(&s[15..]));
-        match opt {
-            Some(s) => Ok(s),
-            None => self.generate_repo_err("list branches", "unable to filter main branch"),
+    async fn local_work(&self) -> Result<LocalWork> {
+        let string_path = self.path();
+        let path = Path::new(&string_path);
+        let status = self
+            .exec_resolve("git status", "git status --porcelain", path)
+            .await?;
+        let unpushed = self
+            .exec_resolve(
+                "list unpushed commits",
+                "git rev-list -1 HEAD --not --remotes",
+                path,
+            )
+            .await?;
+        Ok(LocalWork {
+            uncommitted: !String::from_utf8_lossy(&status.stdout).trim().is_empty(),
+            unpushed: !String::from_utf8_lossy(&unpushed.stdout).trim().is_empty(),
+        })
+    }
+
+    /// Stash uncommitted changes and branch off unpushed commits, with timestamped names.
+    /// Returns where the work was saved.
+    async fn back_up(&self, local_work: &LocalWork) -> Result<Vec<String>> {
+        let string_path = self.path();
+        let path = Path::new(&string_path);
+        let stamp = timestamp(unix_now());
+        let mut backups: Vec<String> = vec![];
+        if local_work.uncommitted {
+            let message = format!("bitbucket_server_cli-reset-{}", stamp);
+            self.act(
+                "stash before reset",
+                &format!("git stash push --include-untracked -m {}", message),
+                path,
+            )
+            .await?;
+            backups.push(format!("stash {}", message));
         }
+        if local_work.unpushed {
+            let branch = format!("backup/reset-{}", stamp);
+            self.act(
+                "backup branch before reset",
+                &format!("git branch {} HEAD", branch),
+                path,
+            )
+            .await?;
+            backups.push(format!("branch {}", branch));
+        }
+        Ok(backups)
     }
 
     async fn git_reset(&self) -> Result<()> {
         let string_path = self.path();
         let path = Path::new(&string_path);
-        match exec("git reset --hard", path).await {
-            Ok(_) => {
-                let main_branch: String = self.get_git_main().await?;
-                match exec(
-                    &format!("git checkout {} --quiet --force", main_branch),
-                    path,
-                )
-                .await
-                {
-                    Err(e) => self.generate_repo_err(&format!("checkout {}", main_branch), &e.msg),
-                    Ok(_) => Ok(()),
-                }
-            }
-            Err(e) => self.generate_repo_err("resetting repo", &e.msg),
+        if let Err(e) = self
+            .act_unchecked("resetting repo", "git reset --hard", path)
+            .await
+        {
+            return self.generate_repo_err(e.kind, "resetting repo", &e.msg);
         }
+        let main_branch: String = self.get_git_main().await?;
+        let checkout = format!("checkout {}", main_branch);
+        if let Err(e) = self
+            .act_unchecked(
+                &checkout,
+                &format!("git checkout {} --quiet --force", main_branch),
+                path,
+            )
+            .await
+        {
+            return self.generate_repo_err(e.kind, &checkout, &e.msg);
+        }
+        if self.opts.reset_clean {
+            self.act("git clean", "git clean -fdx --quiet", path)
+                .await?;
+        }
+        Ok(())
     }
 
     fn generate_repo_err_from_output<T>(
@@ -165,33 +970,162 @@ impl SingleGit<'_, '_> {
         cause_out: Vec<u8>,
         cause_err: Vec<u8>,
     ) -> Result<T> {
+        let failure = git_failure(&String::from_utf8_lossy(&cause_err));
         let cause = match (cause_to_str(cause_err), cause_to_str(cause_out)) {
             (Some(e), _) => format!("Err: '{}'", e.trim()),
             (_, Some(o)) => format!("Output: '{}'", o.trim()),
             (None, None) => "no output".to_string(),
         };
-        self.generate_repo_err(suffix, &cause)
+        self.generate_repo_err(ErrorKind::Git(failure), suffix, &cause)
     }
 
-    fn path(&self) -> String {
-        return format!(
-            "{}/{}/{}",
-            &self.opts.output_directory, &self.repo.project_key, &self.repo.name
-        );
+    pub fn path(&self) -> String {
+        format!(
+            "{}/{}",
+            &self.opts.output_directory,
+            self.layout().relative_path(self.repo)
+        )
+    }
+
+    fn layout(&self) -> Layout<'_> {
+        Layout::new(self.opts)
     }
 
-    fn dir_exists(&self) -> bool {
+    pub fn dir_exists(&self) -> bool {
         Path::new(&self.path()).exists()
     }
 
-    fn generate_repo_err<T>(&self, action: &str, cause: &str) -> Result<T> {
-        bail(&format!(
-            "{}/{} failed {}. Cause: {}",
-            &self.repo.project_key,
-            self.repo.name,
-            action,
-            cause.lines().next().unwrap_or("unknown")
-        ))
+    /// Whether the clone knows the default branch from origin/HEAD, which is never packed.
+    pub fn has_origin_head(&self) -> bool {
+        Path::new(&self.path())
+            .join(".git/refs/remotes/origin/HEAD")
+            .exists()
+    }
+
+    fn generate_repo_err<T>(&self, kind: ErrorKind, action: &str, cause: &str) -> Result<T> {
+        fail(
+            kind,
+            &format!(
+                "{}/{} failed {}. Cause: {}",
+                &self.repo.project_key,
+                self.repo.name,
+                action,
+                cause.lines().next().unwrap_or("unknown")
+            ),
+        )
+    }
+}
+
+/// Where a repo's branches live in the reference cache, '~' is not allowed in ref names.
+fn cache_namespace(repo: &Repo) -> String {
+    match repo.project_key.strip_prefix('~') {
+        Some(user) => format!("users/{}/{}", user, repo.name),
+        None => format!("projects/{}/{}", repo.project_key, repo.name),
+    }
+}
+
+/// What kind of trouble git ran into, going by what it printed.
+fn git_failure(stderr: &str) -> GitFailure {
+    let stderr = stderr.to_lowercase();
+    let mentions = |patterns: &[&str]| patterns.iter().any(|p| stderr.contains(p));
+    if mentions(&[
+        "authentication failed",
+        "permission denied (publickey",
+        "could not read username",
+        "could not read password",
+        "host key verification failed",
+        "returned error: 401",
+        "returned error: 403",
+    ]) {
+        GitFailure::Auth
+    } else if mentions(&[
+        "repository not found",
+        "does not appear to be a git repository",
+        "returned error: 404",
+    ]) {
+        GitFailure::NotFound
+    } else if is_transient(&stderr) {
+        GitFailure::Network
+    } else if mentions(&[
+        "conflict",
+        "not possible to fast-forward",
+        "would be overwritten",
+        "diverging branches",
+        "non-fast-forward",
+        "unmerged",
+    ]) {
+        GitFailure::Conflict
+    } else {
+        GitFailure::Other
+    }
+}
+
+/// Whether git failed on something that may well work when tried again.
+fn is_transient(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    [
+        "early eof",
+        "connection reset",
+        "connection refused",
+        "connection timed out",
+        "operation timed out",
+        "the remote end hung up unexpectedly",
+        "unexpected disconnect",
+        "too many connections",
+        "rpc failed",
+        "temporary failure in name resolution",
+        "could not resolve host",
+        "could not connect to server",
+        "failed to connect to",
+        "kex_exchange_identification",
+        "ssh_exchange_identification",
+        "returned error: 502",
+        "returned error: 503",
+        "returned error: 504",
+    ]
+    .iter()
+    .any(|pattern| stderr.contains(pattern))
+}
+
+fn uses_lfs(gitattributes: &str) -> bool {
+    gitattributes
+        .lines()
+        .map(|l| l.trim())
+        .any(|l| !l.starts_with('#') && l.split_whitespace().any(|a| a == "filter=lfs"))
+}
+
+/// Whether 'git submodule status' shows submodules not checked out at the recorded commit.
+fn submodules_missing(status: &str) -> bool {
+    status
+        .lines()
+        .any(|l| l.starts_with('-') || l.starts_with('+') || l.starts_with('U'))
+}
+
+/// Whether 'git lfs ls-files' shows files that are only pointers, marked with '-'.
+fn lfs_missing(ls_files: &str) -> bool {
+    ls_files
+        .lines()
+        .any(|l| l.split_whitespace().nth(1) == Some("-"))
+}
+
+/// The branch in 'ref: refs/heads/main\tHEAD' of 'git ls-remote --symref'.
+fn main_from_symref(ls_remote: &str) -> Option<String> {
+    ls_remote
+        .lines()
+        .filter_map(|l| l.strip_prefix("ref: refs/heads/"))
+        .find_map(|l| l.strip_suffix("\tHEAD"))
+        .map(|b| b.to_owned())
+}
+
+/// Hidden sibling of the clone directory, ie. 'plat/.api.clone-tmp' for 'plat/api'.
+fn tmp_clone_path(relative_path: &str) -> String {
+    match relative_path.rfind('/') {
+        Some(i) => format!(
+            "{}/.{}.clone-tmp",
+            &relative_path[..i],
+            &relative_path[i + 1..]
+        ),
+        None => format!(".{}.clone-tmp", relative_path),
     }
 }
 
@@ -208,6 +1142,8 @@ fn cause_to_str(cause: Vec<u8>) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::{test_opts, test_repo};
+    use crate::util::exec;
 
     #[cfg(not(target_os = "windows"))]
     const RM_STR: &str = "rm -rf test_repo";
@@ -216,9 +1152,8 @@ mod tests {
 
     fn repo(project_key: &str, name: &str) -> Repo {
         Repo {
-            project_key: String::from(project_key),
             git: String::from("https://github.com/jensim/bitbucket_server_cli.git"),
-            name: String::from(name),
+            ..test_repo(project_key, name)
         }
     }
 
@@ -228,13 +1163,7 @@ mod tests {
         let repo = repo("test_project", "test_repo");
         let project_path = "/tmp/test_project";
         let output_directory = "/tmp";
-        let opts = GitOpts {
-            reset_state: false,
-            concurrency: 1,
-            quiet: false,
-            output_directory: output_directory.to_owned(),
-            ssl_allow_anything: false,
-        };
+        let opts = test_opts(output_directory);
         std::fs::create_dir_all(project_path).unwrap();
         assert!(
             Path::new(project_path).exists(),
@@ -257,36 +1186,568 @@ mod tests {
         assert!(!Path::new(repo_path).exists(), "Failed cleaning away dir.");
     }
 
-    #[test]
-    fn test_get_git_main_from_remote_info() {
-        //let repo_path = "/tmp/test_project/test_repo";
-        let repo = repo("test_project", "test_repo");
-        let project_path = "/tmp/test_project";
-        let output_directory = "/tmp";
-        let opts = GitOpts {
-            reset_state: false,
-            concurrency: 1,
-            quiet: false,
-            output_directory: output_directory.to_owned(),
-            ssl_allow_anything: false,
+    /// A bare 'origin' with one commit, so clones can be tested without network.
+    async fn local_origin(root: &str, project_key: &str, name: &str) -> String {
+        let _ = std::fs::remove_dir_all(root);
+        let seed = format!("{}/seed", root);
+        let origin = format!("{}/origin/{}/{}.git", root, project_key, name);
+        std::fs::create_dir_all(&seed).unwrap();
+        std::fs::create_dir_all(&origin).unwrap();
+        let init_bare = "git init -q --bare && git symbolic-ref HEAD refs/heads/master";
+        assert!(exec(init_bare, &origin).await.unwrap().status.success());
+        for cmd in &[
+            "git init -q",
+            "git -c user.name=test -c user.email=test@example.com commit -q --allow-empty -m init",
+            &format!("git push -q {} HEAD:refs/heads/master", origin),
+        ] {
+            assert!(exec(cmd, &seed).await.unwrap().status.success(), "{}", cmd);
+        }
+        origin
+    }
+
+    #[tokio::test]
+    async fn test_reclone_broken() {
+        let root = "/tmp/bitbucket_server_cli_test_broken";
+        let mut repo = repo("plat", "api");
+        let output_directory = format!("{}/out", root);
+        let mut opts = test_opts(&output_directory);
+        repo.git = local_origin(root, "plat", "api").await;
+        let repo_path = format!("{}/plat/api", output_directory);
+        std::fs::create_dir_all(&repo_path).unwrap();
+        std::fs::write(format!("{}/notes.txt", repo_path), "not a clone").unwrap();
+
+        let single = SingleGit::new(&repo, &opts);
+        assert_eq!(
+            single.broken_reason().await,
+            Some("not a git repository".to_owned())
+        );
+        assert!(single.clone_or_update().await.is_err());
+
+        opts.reclone_broken = true;
+        let single = SingleGit::new(&repo, &opts);
+        single.clone_or_update().await.unwrap();
+        assert_eq!(single.broken_reason().await, None);
+        assert!(!Path::new(&format!("{}/plat/.api.clone-tmp", output_directory)).exists());
+        let moved_aside = std::fs::read_dir(format!("{}/plat", output_directory))
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .any(|e| e.file_name().to_string_lossy().starts_with("api.broken-"));
+        assert!(moved_aside, "Broken dir should be kept aside");
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_sync_remote() {
+        let root = "/tmp/bitbucket_server_cli_test_remote";
+        let mut repo = repo("plat", "api");
+        let opts = test_opts(&format!("{}/out", root));
+        repo.git = local_origin(root, "plat", "api").await;
+        std::fs::create_dir_all(format!("{}/out/plat", root)).unwrap();
+        let outcome = SingleGit::new(&repo, &opts)
+            .clone_or_update()
+            .await
+            .unwrap();
+        assert!(outcome.cloned);
+        assert_eq!(outcome.remote_change, None);
+
+        repo.git = format!("file://{}", repo.git);
+        repo.push_url = Some("ssh://git@host:7999/plat/api.git".to_owned());
+        let single = SingleGit::new(&repo, &opts);
+        let outcome = single.clone_or_update().await.unwrap();
+        assert!(outcome.remote_change.is_some());
+        assert_eq!(
+            single.git_config("remote.origin.url").await,
+            Some(repo.git.clone())
+        );
+        assert_eq!(
+            single.git_config("remote.origin.pushurl").await,
+            repo.push_url
+        );
+        let outcome = single.clone_or_update().await.unwrap();
+        assert_eq!(outcome.remote_change, None);
+        assert!(outcome.unchanged, "Nothing new on the remote");
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_reset_backs_up_local_work() {
+        let root = "/tmp/bitbucket_server_cli_test_reset";
+        let mut repo = repo("plat", "api");
+        let mut opts = test_opts(&format!("{}/out", root));
+        opts.reset_state = true;
+        opts.reset_skip_unpushed = true;
+        repo.git = local_origin(root, "plat", "api").await;
+        std::fs::create_dir_all(format!("{}/out/plat", root)).unwrap();
+        SingleGit::new(&repo, &opts)
+            .clone_or_update()
+            .await
+            .unwrap();
+        let clone = format!("{}/out/plat/api", root);
+        for cmd in &[
+            "git config user.name test",
+            "git config user.email test@example.com",
+            "git commit -q --allow-empty -m local",
+        ] {
+            assert!(exec(cmd, &clone).await.unwrap().status.success(), "{}", cmd);
+        }
+        std::fs::write(format!("{}/wip.txt", clone), "work in progress").unwrap();
+
+        let outcome = SingleGit::new(&repo, &opts)
+            .clone_or_update()
+            .await
+            .unwrap();
+        assert!(outcome.reset_skipped);
+        assert!(!outcome.reset);
+        assert!(outcome.backups.is_empty());
+
+        opts.reset_skip_unpushed = false;
+        let outcome = SingleGit::new(&repo, &opts)
+            .clone_or_update()
+            .await
+            .unwrap();
+        assert!(!outcome.reset_skipped);
+        assert!(outcome.reset);
+        assert_eq!(outcome.backups.len(), 2, "{:?}", outcome.backups);
+        assert!(outcome.new_head.is_some());
+        let stashes = exec("git stash list", &clone).await.unwrap().stdout;
+        assert!(String::from_utf8_lossy(&stashes).contains("bitbucket_server_cli-reset-"));
+        let branches = exec("git branch --list backup/*", &clone)
+            .await
+            .unwrap()
+            .stdout;
+        assert!(String::from_utf8_lossy(&branches).contains("backup/reset-"));
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_changes_nothing() {
+        let root = "/tmp/bitbucket_server_cli_test_dry_run";
+        let mut repo = repo("plat", "api");
+        let mut opts = test_opts(&format!("{}/out", root));
+        opts.reset_state = true;
+        opts.dry_run = true;
+        repo.git = local_origin(root, "plat", "api").await;
+        std::fs::create_dir_all(format!("{}/out/plat", root)).unwrap();
+        let clone = format!("{}/out/plat/api", root);
+        let outcome = SingleGit::new(&repo, &opts)
+            .clone_or_update()
+            .await
+            .unwrap();
+        assert!(outcome.cloned);
+        assert!(
+            outcome.plan[0].starts_with("git clone"),
+            "{:?}",
+            outcome.plan
+        );
+        assert!(!Path::new(&clone).exists());
+
+        opts.dry_run = false;
+        SingleGit::new(&repo, &opts)
+            .clone_or_update()
+            .await
+            .unwrap();
+        opts.dry_run = true;
+        opts.reset_state = false;
+        let outcome = SingleGit::new(&repo, &opts)
+            .clone_or_update()
+            .await
+            .unwrap();
+        assert!(outcome.unchanged);
+        assert!(outcome.plan.is_empty(), "{:?}", outcome.plan);
+
+        std::fs::write(format!("{}/wip.txt", clone), "work in progress").unwrap();
+        opts.reset_state = true;
+        let outcome = SingleGit::new(&repo, &opts)
+            .clone_or_update()
+            .await
+            .unwrap();
+        assert!(outcome.reset);
+        assert!(outcome.plan.iter().any(|s| s.starts_with("git stash push")));
+        assert!(outcome.plan.contains(&"git reset --hard".to_owned()));
+        assert!(Path::new(&format!("{}/wip.txt", clone)).exists());
+        let stashes = exec("git stash list", &clone).await.unwrap().stdout;
+        assert!(stashes.is_empty());
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_deleted_remote_branch_is_unchanged_after_update() {
+        let root = "/tmp/bitbucket_server_cli_test_pruned";
+        let mut repo = repo("plat", "api");
+        let opts = test_opts(&format!("{}/out", root));
+        repo.git = local_origin(root, "plat", "api").await;
+        let seed = format!("{}/seed", root);
+        let push = format!("git push -q {} HEAD:refs/heads/develop", repo.git);
+        assert!(exec(&push, &seed).await.unwrap().status.success());
+        std::fs::create_dir_all(format!("{}/out/plat", root)).unwrap();
+        let single = SingleGit::new(&repo, &opts);
+        single.clone_or_update().await.unwrap();
+        let delete = format!("git push -q {} :refs/heads/develop", repo.git);
+        assert!(exec(&delete, &seed).await.unwrap().status.success());
+
+        assert!(!single.clone_or_update().await.unwrap().unchanged);
+        assert!(single.clone_or_update().await.unwrap().unchanged);
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_failed_submodule_is_retried_when_unchanged() {
+        let root = "/tmp/bitbucket_server_cli_test_submodules";
+        let mut repo = repo("plat", "api");
+        let mut opts = test_opts(&format!("{}/out", root));
+        opts.submodules = true;
+        repo.git = local_origin(root, "plat", "api").await;
+        let lib = format!("{}/lib", root);
+        let lib_moved = format!("{}/lib-moved", root);
+        let seed = format!("{}/seed", root);
+        for (cmd, dir) in &[
+            ("git init -q && git -c user.name=test -c user.email=test@example.com commit -q --allow-empty -m lib".to_owned(), &lib),
+            (format!(
+                "git -c protocol.file.allow=always submodule add -q {} lib && git -c user.name=test -c user.email=test@example.com commit -q -m sub \
+                 && git push -q {} HEAD:refs/heads/master",
+                lib, repo.git
+            ), &seed),
+        ] {
+            std::fs::create_dir_all(dir).unwrap();
+            assert!(exec(cmd, dir).await.unwrap().status.success(), "{}", cmd);
+        }
+        std::fs::rename(&lib, &lib_moved).unwrap();
+        std::fs::create_dir_all(format!("{}/out/plat", root)).unwrap();
+        let single = SingleGit::new(&repo, &opts);
+        let outcome = single.clone_or_update().await.unwrap();
+        assert!(!outcome.extras_errors.is_empty(), "{:?}", outcome);
+
+        // Submodule clones may not use the file protocol, so hand the clone its
+        // submodule repo the way an earlier, interrupted update would have left it.
+        let modules = format!("{}/out/plat/api/.git/modules", root);
+        std::fs::create_dir_all(&modules).unwrap();
+        let seed_module = format!(
+            "git clone -q --bare {} lib && git --git-dir=lib config core.bare false",
+            lib_moved
+        );
+        assert!(exec(&seed_module, &modules).await.unwrap().status.success());
+        let outcome = single.clone_or_update().await.unwrap();
+        assert!(outcome.unchanged);
+        assert!(outcome.extras_errors.is_empty(), "{:?}", outcome);
+        let sub_head = format!("{}/out/plat/api/lib/.git", root);
+        assert!(
+            Path::new(&sub_head).exists(),
+            "Submodule should be checked out"
+        );
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_fast_forward_branches() {
+        let root = "/tmp/bitbucket_server_cli_test_branches";
+        let mut repo = repo("plat", "api");
+        let mut opts = test_opts(&format!("{}/out", root));
+        opts.update_all_branches = true;
+        repo.git = local_origin(root, "plat", "api").await;
+        let seed = format!("{}/seed", root);
+        let commit = "git -c user.name=test -c user.email=test@example.com commit -q --allow-empty";
+        for branch in &["develop", "feature"] {
+            let cmd = format!("git push -q {} HEAD:refs/heads/{}", repo.git, branch);
+            assert!(exec(&cmd, &seed).await.unwrap().status.success());
+        }
+        std::fs::create_dir_all(format!("{}/out/plat", root)).unwrap();
+        SingleGit::new(&repo, &opts)
+            .clone_or_update()
+            .await
+            .unwrap();
+        let clone = format!("{}/out/plat/api", root);
+        for cmd in &[
+            "git branch -q develop origin/develop".to_owned(),
+            "git checkout -q -b feature origin/feature".to_owned(),
+            format!("{} -m local", commit),
+            "git checkout -q master".to_owned(),
+        ] {
+            assert!(exec(cmd, &clone).await.unwrap().status.success(), "{}", cmd);
+        }
+        for branch in &["develop", "feature"] {
+            let cmd = format!(
+                "{} -m {} && git push -q {} HEAD:refs/heads/{}",
+                commit, branch, repo.git, branch
+            );
+            assert!(exec(&cmd, &seed).await.unwrap().status.success());
+        }
+
+        let outcome = SingleGit::new(&repo, &opts)
+            .clone_or_update()
+            .await
+            .unwrap();
+        assert_eq!(outcome.updated_branches, vec!["develop"]);
+        assert_eq!(outcome.diverged_branches, vec!["feature"]);
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_follow_default_branch() {
+        let root = "/tmp/bitbucket_server_cli_test_default_branch";
+        let mut repo = repo("plat", "api");
+        let mut opts = test_opts(&format!("{}/out", root));
+        opts.rename_default_branch = true;
+        repo.git = local_origin(root, "plat", "api").await;
+        std::fs::create_dir_all(format!("{}/out/plat", root)).unwrap();
+        let single = SingleGit::new(&repo, &opts);
+        assert!(!single.has_origin_head());
+        single.clone_or_update().await.unwrap();
+        assert!(single.has_origin_head());
+        let rename = "git branch -m master main && git symbolic-ref HEAD refs/heads/main";
+        assert!(exec(rename, &repo.git).await.unwrap().status.success());
+
+        let outcome = single.clone_or_update().await.unwrap();
+        assert_eq!(
+            outcome.default_branch_change,
+            Some("master -> main, renamed local branch".to_owned())
+        );
+        assert!(single.local_branch_exists("main").await);
+        assert!(!single.local_branch_exists("master").await);
+        let outcome = single.clone_or_update().await.unwrap();
+        assert_eq!(outcome.default_branch_change, None);
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_partial_clone() {
+        let root = "/tmp/bitbucket_server_cli_test_partial";
+        let mut repo = repo("plat", "api");
+        let mut opts = test_opts(&format!("{}/out", root));
+        opts.depth = Some(5);
+        opts.partial_repos = vec![
+            "plat/*;depth=1;single-branch;sparse=src".parse().unwrap(),
+            "web/*".parse().unwrap(),
+        ];
+        let origin = local_origin(root, "plat", "api").await;
+        repo.git = format!("file://{}", origin);
+        let seed = format!("{}/seed", root);
+        let commit = "git -c user.name=test -c user.email=test@example.com commit -q -m";
+        let push = format!("git push -q {} HEAD:refs/heads/master", origin);
+        let add_files = format!(
+            "mkdir -p src docs && echo a > src/a && echo b > docs/b && git add . && {} files && {}",
+            commit, push
+        );
+        assert!(exec(&add_files, &seed).await.unwrap().status.success());
+        std::fs::create_dir_all(format!("{}/out/plat", root)).unwrap();
+        let single = SingleGit::new(&repo, &opts);
+        single.clone_or_update().await.unwrap();
+
+        let clone = format!("{}/out/plat/api", root);
+        let git = |cmd: &'static str, dir: String| async move {
+            let out = exec(cmd, &dir).await.unwrap();
+            String::from_utf8_lossy(&out.stdout).trim().to_owned()
         };
-        std::fs::create_dir_all(project_path).unwrap();
+        let count = "git rev-list --count HEAD";
+        assert_eq!(git(count, clone.clone()).await, "1");
+        assert!(Path::new(&format!("{}/src/a", clone)).exists());
+        assert!(!Path::new(&format!("{}/docs/b", clone)).exists());
+
+        let change = format!("echo c > src/c && git add . && {} more && {}", commit, push);
+        assert!(exec(&change, &seed).await.unwrap().status.success());
+        single.clone_or_update().await.unwrap();
+        assert!(Path::new(&format!("{}/src/c", clone)).exists());
+        let shallow = "git rev-parse --is-shallow-repository";
+        assert_eq!(
+            git(shallow, clone.clone()).await,
+            "true",
+            "Update should stay shallow"
+        );
+        assert!(
+            git(count, clone.clone()).await.parse::<u32>().unwrap()
+                < git(count, origin.clone()).await.parse::<u32>().unwrap(),
+            "Update should not fetch the old history"
+        );
+
+        let web = super::tests::repo("web", "app");
+        assert_eq!(
+            SingleGit::new(&web, &opts).partial_clone_args(),
+            " --depth 5 --no-single-branch"
+        );
+        let other = super::tests::repo("pay", "app");
+        assert_eq!(SingleGit::new(&other, &opts).partial_clone_args(), "");
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_reference_cache() {
+        let root = "/tmp/bitbucket_server_cli_test_reference";
+        let mut repo = repo("plat", "api");
+        let mut opts = test_opts(&format!("{}/out", root));
+        opts.reference_cache = Some(format!("{}/cache", root));
+        // A file:// url, as local paths would copy the objects instead of borrowing them.
+        repo.git = format!("file://{}", local_origin(root, "plat", "api").await);
+        let cache = format!("{}/cache", root);
+        std::fs::create_dir_all(&cache).unwrap();
+        assert!(exec("git init -q --bare", &cache)
+            .await
+            .unwrap()
+            .status
+            .success());
+        std::fs::create_dir_all(format!("{}/out/plat", root)).unwrap();
 
         let single = SingleGit::new(&repo, &opts);
-        let s = single.get_git_main_from_remote_info(
-            "* remote origin
-  Fetch URL: ssh://git@github.com/jensim/foo-bar-baz.git
-  Push  URL: ssh://git@github.com/jensim/foo-bar-baz.git
-  HEAD branch: master
-  Remote branches:
-    master                                                       tracked
-  Local branches configured for 'git pull':
-    master                                                       merges with remote master
-  Local refs configured for 'git push':
-    master                                                       pushes to master                                                       (up to date)
-",
-        ).unwrap();
-
-        assert_eq!(s, "master")
+        single
+            .update_reference_cache(Path::new(&cache))
+            .await
+            .unwrap();
+        let outcome = single.clone_or_update().await.unwrap();
+        let alternates = format!("{}/out/plat/api/.git/objects/info/alternates", root);
+        assert!(
+            Path::new(&alternates).exists(),
+            "Clone should borrow objects"
+        );
+        assert!(outcome.shared_bytes > 0);
+        assert_eq!(cache_namespace(&repo), "projects/plat/api");
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_clean_merged_keeps_new_branch_on_old_commit() {
+        let root = "/tmp/bitbucket_server_cli_test_clean_merged";
+        let mut repo = repo("plat", "api");
+        let mut opts = test_opts(&format!("{}/out", root));
+        opts.clean_merged = true;
+        opts.always_fetch = true;
+        repo.git = local_origin(root, "plat", "api").await;
+        let seed = format!("{}/seed", root);
+        let old_commit = format!(
+            "GIT_COMMITTER_DATE='@1000000000 +0000' git -c user.name=test -c user.email=test@example.com \
+             commit -q --allow-empty -m old && git push -q {} HEAD:refs/heads/master",
+            repo.git
+        );
+        assert!(exec(&old_commit, &seed).await.unwrap().status.success());
+        std::fs::create_dir_all(format!("{}/out/plat", root)).unwrap();
+        let single = SingleGit::new(&repo, &opts);
+        single.clone_or_update().await.unwrap();
+        let clone = format!("{}/out/plat/api", root);
+        let branch =
+            "git checkout -q -b fix && git push -q -u origin fix && git checkout -q master";
+        assert!(exec(branch, &clone).await.unwrap().status.success());
+
+        let outcome = single.clone_or_update().await.unwrap();
+        assert!(outcome.removed_branches.is_empty(), "{:?}", outcome);
+        assert!(single.branch_created("fix", Path::new(&clone)).await + 60 > unix_now());
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn test_is_removable() {
+        let repo = repo("plat", "api");
+        let mut opts = test_opts("/tmp");
+        opts.clean_merged = true;
+        opts.protect_branches = vec!["release/*".to_owned(), "develop".to_owned()];
+        let single = SingleGit::new(&repo, &opts);
+        let now = 100 * 24 * 60 * 60;
+        let branch = |name: &str, has_upstream: bool, age_days: u64| MergedBranch {
+            name: name.to_owned(),
+            has_upstream,
+            created: now - age_days * 24 * 60 * 60,
+        };
+
+        assert!(single.is_removable(&branch("feature/done", true, 30), "master", now));
+        assert!(!single.is_removable(&branch("master", true, 30), "master", now));
+        assert!(!single.is_removable(&branch("release/1.0", true, 30), "master", now));
+        assert!(!single.is_removable(&branch("develop", true, 30), "master", now));
+        assert!(!single.is_removable(&branch("feature/new", true, 2), "master", now));
+        assert!(!single.is_removable(&branch("feature/local", false, 30), "master", now));
+    }
+
+    #[test]
+    fn test_tmp_clone_path() {
+        assert_eq!(tmp_clone_path("plat/api"), "plat/.api.clone-tmp");
+        assert_eq!(tmp_clone_path("plat-api"), ".plat-api.clone-tmp");
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(
+            "error: RPC failed; curl 18 transfer closed\nfatal: early EOF\n"
+        ));
+        assert!(is_transient(
+            "fatal: unable to access 'https://host/scm/plat/api.git/': The requested URL returned error: 503\n"
+        ));
+        assert!(is_transient(
+            "fatal: unable to access 'https://host/scm/plat/api.git/': Could not resolve host: host\n"
+        ));
+        assert!(is_transient(
+            "fatal: unable to access 'https://host/scm/plat/api.git/': Failed to connect to host port 443: Connection timed out\n"
+        ));
+        assert!(is_transient(
+            "fatal: unable to access 'https://host/scm/plat/api.git/': Could not connect to server\n"
+        ));
+        assert!(is_transient(
+            "ssh: Could not resolve hostname host: Name or service not known\nfatal: Could not read from remote repository.\n"
+        ));
+        assert!(!is_transient(
+            "fatal: Authentication failed for 'https://host/scm/plat/api.git/'\n"
+        ));
+        assert!(!is_transient(
+            "fatal: Not possible to fast-forward, aborting.\n"
+        ));
+    }
+
+    #[test]
+    fn test_git_failure() {
+        assert_eq!(
+            git_failure("fatal: Authentication failed for 'https://host/scm/plat/api.git/'"),
+            GitFailure::Auth
+        );
+        assert_eq!(
+            git_failure("fatal: 'plat/gone.git' does not appear to be a git repository"),
+            GitFailure::NotFound
+        );
+        assert_eq!(
+            git_failure("fatal: the remote end hung up unexpectedly"),
+            GitFailure::Network
+        );
+        assert_eq!(
+            git_failure("fatal: Not possible to fast-forward, aborting."),
+            GitFailure::Conflict
+        );
+        assert_eq!(git_failure("fatal: bad object HEAD"), GitFailure::Other);
+    }
+
+    #[test]
+    fn test_uses_lfs() {
+        assert!(uses_lfs("*.psd filter=lfs diff=lfs merge=lfs -text\n"));
+        assert!(!uses_lfs(
+            "# *.psd filter=lfs diff=lfs merge=lfs -text\n*.sh eol=lf\n"
+        ));
+        assert!(!uses_lfs(""));
+    }
+
+    #[test]
+    fn test_extras_missing() {
+        assert!(submodules_missing(
+            " 1a2b3c4d libs/core (v1.0)\n-5e6f7a8b libs/ui\n"
+        ));
+        assert!(submodules_missing("+1a2b3c4d libs/core (v1.1)\n"));
+        assert!(!submodules_missing(" 1a2b3c4d libs/core (v1.0)\n"));
+        assert!(lfs_missing("4d7a2146 * logo.psd\n9e1f3c5a - banner.psd\n"));
+        assert!(!lfs_missing("4d7a2146 * logo.psd\n"));
+        assert!(!lfs_missing(""));
+    }
+
+    #[test]
+    fn test_main_from_symref() {
+        let ls_remote = "ref: refs/heads/main\tHEAD\n\
+            4d3c1a0b7e5f0b1f4f2d6c0c9d5e8b7a6f5e4d3c\tHEAD\n";
+        assert_eq!(main_from_symref(ls_remote), Some("main".to_owned()));
+        assert_eq!(
+            main_from_symref("ref: refs/heads/release/2.x\tHEAD\n"),
+            Some("release/2.x".to_owned())
+        );
+        assert_eq!(main_from_symref(""), None);
     }
 }
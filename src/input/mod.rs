@@ -1,18 +1,88 @@
 use std::result::Result as StdResult;
 
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, MultiSelect, Password};
-use generic_error::Result;
 use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
 
 use crate::bitbucket::types::Repo;
-use crate::input::prompts::{Prompt, PROMPT_BB_PASSWORD, PROMPT_BB_PROJECT_SOME};
-use crate::util::bail;
+use crate::error::{ErrorKind, Result};
+use crate::input::prompts::{
+    Prompt, PROMPT_BB_PASSWORD, PROMPT_BB_PROJECT_SOME, PROMPT_BB_REPO_PICK, PROMPT_BB_REPO_SEEN,
+    PROMPT_BB_REPO_SET, PROMPT_BB_REPO_SET_USE, PROMPT_BB_REPO_SOME,
+};
+use crate::input::sets::{load_set, save_set, RepoSet, DEFAULT_SET};
+use crate::util::fail;
 
 pub mod prompts;
+pub mod sets;
 
 const PROP_FILE: &str = ".bitbucket_server_cli.db";
 
-pub fn select_projects(repos: &[Repo]) -> Vec<String> {
+/// Pick a named repo set, or build it by selecting projects and repos.
+/// The selection is saved under the set name and pre-selected on later runs.
+pub fn select_repo_set(repos: &[Repo]) -> Result<RepoSet> {
+    let name = get_with_default(&PROMPT_BB_REPO_SET, Some(DEFAULT_SET.to_owned()), false)
+        .unwrap_or_else(|| DEFAULT_SET.to_owned());
+    let previous: RepoSet = match load_set(&name) {
+        Some(set) => {
+            println!("Repo set {}: {}", name, set.summary());
+            let unoffered = set.unoffered(repos);
+            if !unoffered.is_empty() {
+                let names: Vec<String> = unoffered.iter().map(|r| r.full_name()).collect();
+                println!("New repos since picking: {}", names.join(", "));
+                let set = pick_repos(repos, set.pick_projects.clone(), &set);
+                save_set(&name, &set)?;
+                return Ok(set);
+            }
+            if get_bool(&PROMPT_BB_REPO_SET_USE, true) {
+                return Ok(set);
+            }
+            set
+        }
+        None => RepoSet::default(),
+    };
+    let pre_selected_keys: Vec<String> = previous
+        .project_keys
+        .iter()
+        .chain(previous.pick_projects.iter())
+        .cloned()
+        .collect();
+    let project_keys = select_projects(repos, &pre_selected_keys);
+    let set = if get_bool(&PROMPT_BB_REPO_PICK, false) {
+        pick_repos(repos, project_keys, &previous)
+    } else {
+        RepoSet {
+            project_keys,
+            ..RepoSet::default()
+        }
+    };
+    save_set(&name, &set)?;
+    Ok(set)
+}
+
+/// Pick repos in the given projects. Only the picked repos are in the set, and repos
+/// not offered before are flagged and left unselected.
+fn pick_repos(repos: &[Repo], project_keys: Vec<String>, previous: &RepoSet) -> RepoSet {
+    let in_projects: Vec<Repo> = repos
+        .iter()
+        .filter(|r| project_keys.contains(&r.project_key))
+        .cloned()
+        .collect();
+    let pre_selected: Vec<String> =
+        if previous.pick_projects.is_empty() && previous.repos.is_empty() {
+            in_projects.iter().map(|r| r.full_name()).collect()
+        } else {
+            previous.repos.clone()
+        };
+    let repos = select_repos(&in_projects, &pre_selected, &previous.offered_repos);
+    RepoSet {
+        repos,
+        offered_repos: in_projects.iter().map(|r| r.full_name()).collect(),
+        pick_projects: project_keys,
+        ..RepoSet::default()
+    }
+}
+
+pub fn select_projects(repos: &[Repo], previous: &[String]) -> Vec<String> {
     let mut project_keys: Vec<String> = Vec::new();
     for r in repos {
         if !project_keys.contains(&r.project_key) {
@@ -20,10 +90,6 @@ pub fn select_projects(repos: &[Repo]) -> Vec<String> {
         }
     }
     project_keys.sort();
-    let mut db = get_db();
-    let previous: Vec<String> = db
-        .get(PROMPT_BB_PROJECT_SOME.db_key)
-        .unwrap_or_else(Vec::new);
     let pre_selected: Vec<bool> = project_keys
         .iter()
         .map(|key| previous.contains(key))
@@ -44,23 +110,78 @@ pub fn select_projects(repos: &[Repo]) -> Vec<String> {
     for i in answer {
         filtered.push(project_keys[i].clone());
     }
-    if db.set(PROMPT_BB_PROJECT_SOME.db_key, &filtered).is_err() {
+    filtered
+}
+
+/// Pick individual repos, grouped by project. Returns the selected repos full names.
+/// Repos not in `offered`, or not seen in any earlier selection if it is empty, are flagged as new.
+pub fn select_repos(repos: &[Repo], previous: &[String], offered: &[String]) -> Vec<String> {
+    let mut db = get_db();
+    let seen: Vec<String> = db.get(PROMPT_BB_REPO_SEEN.db_key).unwrap_or_default();
+    let known = if offered.is_empty() { &seen } else { offered };
+    let (names, items, pre_selected) = repo_items(repos, previous, known);
+    let mut answer: Vec<usize> = Vec::new();
+    while answer.is_empty() {
+        answer = MultiSelect::new()
+            .items(&items)
+            .with_prompt(PROMPT_BB_REPO_SOME.prompt_str)
+            .defaults(&pre_selected[..])
+            .interact()
+            .unwrap_or_else(|_e| {
+                eprintln!("Failed handling repo selection");
+                std::process::exit(1);
+            });
+    }
+    let filtered: Vec<String> = answer.into_iter().map(|i| names[i].clone()).collect();
+    let mut all_seen = seen;
+    for name in names {
+        if !all_seen.contains(&name) {
+            all_seen.push(name);
+        }
+    }
+    if db.set(PROMPT_BB_REPO_SEEN.db_key, &all_seen).is_err() {
         eprintln!(
             "Failed writing value {} to prickle db",
-            PROMPT_BB_PROJECT_SOME.db_key
+            PROMPT_BB_REPO_SEEN.db_key
         );
-    };
+    }
     filtered
 }
 
+/// Repo full names, display items and pre-selection, sorted by project.
+/// Repos not seen in an earlier selection are flagged as new.
+fn repo_items(
+    repos: &[Repo],
+    previous: &[String],
+    seen: &[String],
+) -> (Vec<String>, Vec<String>, Vec<bool>) {
+    let mut names: Vec<String> = repos.iter().map(|r| r.full_name()).collect();
+    names.sort();
+    names.dedup();
+    let items: Vec<String> = names
+        .iter()
+        .map(|name| {
+            let (project, repo) = name.split_at(name.find('/').unwrap_or(0));
+            let new_flag = if seen.is_empty() || seen.contains(name) {
+                ""
+            } else {
+                "  (new)"
+            };
+            format!("{:<20} {}{}", project, &repo[1..], new_flag)
+        })
+        .collect();
+    let pre_selected: Vec<bool> = names.iter().map(|n| previous.contains(n)).collect();
+    (names, items, pre_selected)
+}
+
 pub fn password_from_env() -> Result<String> {
     let key = "BITBUCKET_PASSWORD";
     match std::env::var(key) {
         Ok(val) => Ok(val),
-        Err(e) => bail(&format!(
-            "{} is not defined in the environment. {:?}",
-            key, e
-        )),
+        Err(e) => fail(
+            ErrorKind::Config,
+            &format!("{} is not defined in the environment. {:?}", key, e),
+        ),
     }
 }
 
@@ -141,6 +262,28 @@ pub fn get_with_default(
     }
 }
 
+pub fn get_stored_list(prompt: &Prompt) -> Vec<String> {
+    get_db().get(prompt.db_key).unwrap_or_default()
+}
+
+/// Remember the list, or forget the stored one when it is empty.
+pub fn store_list(prompt: &Prompt, list: &[String]) {
+    if list.is_empty() {
+        return clear_stored(prompt);
+    }
+    let mut db = get_db();
+    if db.set(prompt.db_key, &list).is_err() {
+        eprintln!("Failed writing value {} to prickle db", prompt.db_key)
+    }
+}
+
+pub fn clear_stored(prompt: &Prompt) {
+    let mut db = get_db();
+    if db.rem(prompt.db_key).is_err() {
+        eprintln!("Failed removing value {} from prickle db", prompt.db_key)
+    }
+}
+
 fn resolve(result: StdResult<String, std::io::Error>) -> Option<String> {
     match result {
         Ok(s) if !s.is_empty() => Some(s),
@@ -152,6 +295,8 @@ fn resolve(result: StdResult<String, std::io::Error>) -> Option<String> {
 mod tests {
     use std::fs::remove_file;
 
+    use crate::types::test_repo as repo;
+
     use super::*;
 
     #[test]
@@ -180,4 +325,18 @@ mod tests {
         let verbose: Option<bool> = db.get("poo");
         assert_eq!(verbose, Some(bool_val));
     }
+
+    #[test]
+    fn test_repo_items() {
+        let repos = vec![repo("plat", "b"), repo("app", "x"), repo("plat", "a")];
+        let previous = vec!["plat/a".to_owned()];
+        let seen = vec!["plat/a".to_owned(), "plat/b".to_owned()];
+
+        let (names, items, pre_selected) = repo_items(&repos, &previous, &seen);
+
+        assert_eq!(names, vec!["app/x", "plat/a", "plat/b"]);
+        assert!(items[0].ends_with("x  (new)"), "Was '{}'", items[0]);
+        assert!(items[1].starts_with("plat ") && items[1].ends_with(" a"));
+        assert_eq!(pre_selected, vec![false, true, false]);
+    }
 }
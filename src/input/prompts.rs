@@ -23,3 +23,43 @@ pub const PROMPT_BB_PASSWORD: Prompt = Prompt {
     db_key: "bb_password",
     prompt_str: "BitBucket password",
 };
+pub const PROMPT_BB_REPO_INCLUDE: Prompt = Prompt {
+    db_key: "bb_repo_include",
+    prompt_str: "Include repos matching",
+};
+pub const PROMPT_BB_REPO_EXCLUDE: Prompt = Prompt {
+    db_key: "bb_repo_exclude",
+    prompt_str: "Exclude repos matching",
+};
+pub const PROMPT_BB_PROJECT_EXCLUDE: Prompt = Prompt {
+    db_key: "bb_project_exclude",
+    prompt_str: "Exclude projects matching",
+};
+pub const PROMPT_BB_REPO_FILTERS_STORED: Prompt = Prompt {
+    db_key: "bb_repo_filters_stored",
+    prompt_str: "Apply stored repo filters",
+};
+pub const PROMPT_BB_REPO_PICK: Prompt = Prompt {
+    db_key: "bb_repo_pick",
+    prompt_str: "Pick individual repos",
+};
+pub const PROMPT_BB_REPO_SOME: Prompt = Prompt {
+    db_key: "bb_repo_some",
+    prompt_str: "Clone/update some repos",
+};
+pub const PROMPT_BB_REPO_SET: Prompt = Prompt {
+    db_key: "bb_repo_set",
+    prompt_str: "Repo set",
+};
+pub const PROMPT_BB_REPO_SET_USE: Prompt = Prompt {
+    db_key: "bb_repo_set_use",
+    prompt_str: "Use the saved repo set",
+};
+pub const PROMPT_BB_REPO_SEEN: Prompt = Prompt {
+    db_key: "bb_repo_seen",
+    prompt_str: "Repos offered in earlier selections",
+};
+pub const PROMPT_GIT_RECLONE_BROKEN: Prompt = Prompt {
+    db_key: "git_reclone_broken",
+    prompt_str: "Move them aside and clone again",
+};
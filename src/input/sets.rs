@@ -0,0 +1,269 @@
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+
+use crate::bitbucket::types::Repo;
+use crate::error::{ErrorKind, Result};
+use crate::filter::Pattern;
+use crate::input::get_db;
+use crate::input::prompts::PROMPT_BB_PROJECT_SOME;
+use crate::types::{SaveSetOpts, SetsOpts};
+use crate::util::fail;
+
+const SETS_FILE: &str = ".bitbucket_server_cli.sets.db";
+pub const DEFAULT_SET: &str = "default";
+
+/// A named selection of repos, matched by project key, repo pattern, repo full name or user.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RepoSet {
+    pub project_keys: Vec<String>,
+    pub include: Vec<String>,
+    pub repos: Vec<String>,
+    pub users: Vec<String>,
+    /// Projects the repos were picked in, to ask about repos added there later.
+    #[serde(default)]
+    pub pick_projects: Vec<String>,
+    /// Repos in the pick projects that were offered when picking, picked or not.
+    #[serde(default)]
+    pub offered_repos: Vec<String>,
+}
+
+impl RepoSet {
+    pub fn is_empty(&self) -> bool {
+        self.project_keys.is_empty()
+            && self.include.is_empty()
+            && self.repos.is_empty()
+            && self.users.is_empty()
+    }
+
+    pub fn apply(&self, repos: Vec<Repo>) -> Result<Vec<Repo>> {
+        let patterns: Vec<Pattern> = self
+            .include
+            .iter()
+            .map(|p| Pattern::parse(p))
+            .collect::<Result<Vec<Pattern>>>()?;
+        let users: Vec<String> = self
+            .users
+            .iter()
+            .map(|u| format!("~{}", u.trim_start_matches('~').to_lowercase()))
+            .collect();
+        Ok(repos
+            .into_iter()
+            .filter(|r| {
+                self.project_keys.contains(&r.project_key)
+                    || users.contains(&r.project_key)
+                    || self.repos.contains(&r.full_name())
+                    || patterns.iter().any(|p| p.matches_repo(r))
+            })
+            .collect())
+    }
+
+    pub fn summary(&self) -> String {
+        let mut parts: Vec<String> = vec![];
+        for (name, values) in &[
+            ("keys", &self.project_keys),
+            ("include", &self.include),
+            ("repos", &self.repos),
+            ("users", &self.users),
+        ] {
+            if !values.is_empty() {
+                parts.push(format!("{}: {}", name, values.join(", ")));
+            }
+        }
+        parts.join("; ")
+    }
+
+    /// Repos in the projects picked from that were not there when picking.
+    pub fn unoffered(&self, repos: &[Repo]) -> Vec<Repo> {
+        repos
+            .iter()
+            .filter(|r| self.pick_projects.contains(&r.project_key))
+            .filter(|r| !self.offered_repos.contains(&r.full_name()))
+            .cloned()
+            .collect()
+    }
+}
+
+pub fn get_sets_db() -> PickleDb {
+    PickleDb::load(
+        SETS_FILE,
+        PickleDbDumpPolicy::AutoDump,
+        SerializationMethod::Json,
+    )
+    .unwrap_or_else(|_| {
+        PickleDb::new(
+            SETS_FILE,
+            PickleDbDumpPolicy::AutoDump,
+            SerializationMethod::Json,
+        )
+    })
+}
+
+pub fn load_set(name: &str) -> Option<RepoSet> {
+    let db = get_sets_db();
+    match db.get::<RepoSet>(name) {
+        Some(set) => Some(set),
+        None if name == DEFAULT_SET && db.total_keys() == 0 => legacy_project_selection(),
+        None => None,
+    }
+}
+
+pub fn get_set(name: &str) -> Result<RepoSet> {
+    match load_set(name) {
+        Some(set) => Ok(set),
+        None => fail(ErrorKind::Config, &format!("No repo set named {}", name)),
+    }
+}
+
+pub fn save_set(name: &str, set: &RepoSet) -> Result<()> {
+    if name.trim().is_empty() {
+        fail(ErrorKind::Config, "Repo set name must not be empty")?;
+    }
+    let mut db = get_sets_db();
+    match db.set(name, set) {
+        Ok(_) => Ok(()),
+        Err(e) => fail(
+            ErrorKind::Io,
+            &format!("Failed writing repo set {}. {:?}", name, e),
+        ),
+    }
+}
+
+pub fn list_sets() -> Vec<(String, RepoSet)> {
+    let db = get_sets_db();
+    let mut names: Vec<String> = db.get_all();
+    names.sort();
+    names
+        .into_iter()
+        .filter_map(|name| db.get::<RepoSet>(&name).map(|set| (name, set)))
+        .collect()
+}
+
+pub fn run_sets_command(opts: SetsOpts) -> Result<()> {
+    match opts {
+        SetsOpts::List => {
+            for (name, set) in list_sets() {
+                println!("{}\t{}", name, set.summary());
+            }
+            Ok(())
+        }
+        SetsOpts::Save(SaveSetOpts {
+            name,
+            project_keys,
+            include,
+            repos,
+            users,
+        }) => {
+            let set = RepoSet {
+                project_keys: project_keys.iter().map(|k| k.to_lowercase()).collect(),
+                include,
+                repos: repos.iter().map(|r| r.to_lowercase()).collect(),
+                users,
+                ..RepoSet::default()
+            };
+            if set.is_empty() {
+                fail(
+                    ErrorKind::Config,
+                    "A repo set needs at least one key, include pattern, repo or user",
+                )?;
+            }
+            for pattern in &set.include {
+                Pattern::parse(pattern)?;
+            }
+            save_set(&name, &set)
+        }
+        SetsOpts::Delete { name } => {
+            let mut db = get_sets_db();
+            match db.rem(&name) {
+                Ok(true) => Ok(()),
+                Ok(false) => fail(ErrorKind::Config, &format!("No repo set named {}", name)),
+                Err(e) => fail(
+                    ErrorKind::Io,
+                    &format!("Failed removing repo set {}. {:?}", name, e),
+                ),
+            }
+        }
+    }
+}
+
+/// Project keys remembered by versions that kept a single selection in the prompt db.
+fn legacy_project_selection() -> Option<RepoSet> {
+    get_db()
+        .get::<Vec<String>>(PROMPT_BB_PROJECT_SOME.db_key)
+        .map(|project_keys| RepoSet {
+            project_keys,
+            ..RepoSet::default()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::test_repo as repo;
+
+    use super::*;
+
+    #[test]
+    fn test_set_apply() {
+        let set = RepoSet {
+            project_keys: vec!["pay".to_owned()],
+            include: vec!["front/*-app".to_owned()],
+            repos: vec!["lib/money".to_owned()],
+            users: vec!["Jensim".to_owned()],
+            ..RepoSet::default()
+        };
+        let repos = vec![
+            repo("pay", "gateway"),
+            repo("front", "shop-app"),
+            repo("front", "shop-lib"),
+            repo("lib", "money"),
+            repo("lib", "time"),
+            repo("~jensim", "dotfiles"),
+        ];
+
+        let names: Vec<String> = set
+            .apply(repos)
+            .unwrap()
+            .iter()
+            .map(|r| r.full_name())
+            .collect();
+
+        assert_eq!(
+            names,
+            vec![
+                "pay/gateway",
+                "front/shop-app",
+                "lib/money",
+                "~jensim/dotfiles"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_unoffered() {
+        let set = RepoSet {
+            repos: vec!["pay/gateway".to_owned()],
+            pick_projects: vec!["pay".to_owned()],
+            offered_repos: vec!["pay/gateway".to_owned(), "pay/legacy".to_owned()],
+            ..RepoSet::default()
+        };
+        let repos = vec![
+            repo("pay", "gateway"),
+            repo("pay", "legacy"),
+            repo("pay", "refunds"),
+            repo("lib", "money"),
+        ];
+
+        let unoffered: Vec<String> = set
+            .unoffered(&repos)
+            .iter()
+            .map(|r| r.full_name())
+            .collect();
+        let picked: Vec<String> = set
+            .apply(repos)
+            .unwrap()
+            .iter()
+            .map(|r| r.full_name())
+            .collect();
+
+        assert_eq!(unoffered, vec!["pay/refunds"]);
+        assert_eq!(picked, vec!["pay/gateway"]);
+    }
+}
@@ -10,6 +10,11 @@ pub mod types;
 
 mod bitbucket;
 pub mod completion;
+pub mod error;
+mod filter;
 mod git;
 mod input;
+pub mod lister;
+pub use input::sets;
+mod state;
 pub mod util;
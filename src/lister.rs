@@ -0,0 +1,44 @@
+use crate::bitbucket::types::Repo;
+use crate::bitbucket::worker::BitbucketWorker;
+use crate::error::Result;
+use crate::filter::RepoFilter;
+use crate::input::sets::get_set;
+use crate::types::{Exit, ListOpts};
+
+pub struct Lister {
+    opts: ListOpts,
+}
+
+impl Lister {
+    pub fn new(opts: ListOpts) -> Result<Lister> {
+        let mut opts = opts;
+        opts.validate()?;
+        Ok(Lister { opts })
+    }
+
+    pub async fn list_repos(self) -> Result<Exit> {
+        let bb = BitbucketWorker::new(&self.opts.bitbucket_opts);
+        let repos: Vec<Repo> = match bb.fetch_all_repos().await {
+            Ok(r) => r,
+            Err(e) => Err(e.context("Failed fetching user & project repos."))?,
+        };
+        let repos = bb.attach_labels(repos).await?;
+        let mut repos = RepoFilter::new(&self.opts.bitbucket_opts)?.apply(repos);
+        if let Some(name) = &self.opts.bitbucket_opts.set {
+            repos = get_set(name)?.apply(repos)?;
+        }
+        if self.opts.show_labels {
+            repos = bb.fetch_repo_labels(repos).await;
+        }
+        repos.sort_by(|a, b| (&a.project_key, &a.name).cmp(&(&b.project_key, &b.name)));
+        for repo in repos {
+            println!(
+                "{}\t{}\t{}",
+                repo.full_name(),
+                repo.git,
+                repo.labels.join(",")
+            );
+        }
+        Ok(Exit::Success)
+    }
+}
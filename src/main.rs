@@ -1,24 +1,35 @@
-use bitbucket_server_cli::{cloner::Cloner, completion::gen_completions, types::Opts};
-use generic_error::{GenericError, Result};
+use bitbucket_server_cli::error::Result;
+use bitbucket_server_cli::{
+    cloner::Cloner,
+    completion::gen_completions,
+    lister::Lister,
+    sets::run_sets_command,
+    types::{Exit, Opts},
+    util::quit_on_interrupt,
+};
 use structopt::StructOpt;
 
 #[tokio::main]
 async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    tokio::spawn(quit_on_interrupt(Exit::Interrupted.code()));
     match act().await {
-        Ok(_) => Ok(()),
-        Err(GenericError { msg }) => {
-            eprintln!("{}", msg);
-            std::process::exit(1);
+        Ok(Exit::Success) => Ok(()),
+        Ok(exit) => std::process::exit(exit.code()),
+        Err(e) => {
+            eprintln!("{}", e.msg);
+            std::process::exit(Exit::from_error(&e).code());
         }
     }
 }
 
-async fn act() -> Result<()> {
+async fn act() -> Result<Exit> {
     let opts: Opts = Opts::from_args();
     match opts {
         Opts::Clone(c) => Cloner::new(c)?.clone_projects_and_users().await,
         Opts::CloneProjects(c) => Cloner::new(c)?.clone_projects().await,
         Opts::CloneUsers(c) => Cloner::new(c)?.clone_users().await,
-        Opts::Completions => gen_completions(),
+        Opts::List(l) => Lister::new(l)?.list_repos().await,
+        Opts::Sets(s) => run_sets_command(s).map(|_| Exit::Success),
+        Opts::Completions => gen_completions().map(|_| Exit::Success),
     }
 }
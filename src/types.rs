@@ -1,12 +1,23 @@
 use std::path::Path;
+use std::str::FromStr;
 
 use clap::arg_enum;
-use generic_error::Result;
 use structopt::StructOpt;
 
-use crate::input::prompts::{PROMPT_BB_PROJECT_ALL, PROMPT_BB_SERVER, PROMPT_BB_USERNAME};
-use crate::input::{get_bool, get_password, get_with_default, password_from_env};
-use crate::util::bail;
+use crate::bitbucket::types::Repo;
+use crate::error::{Error, ErrorKind, Result};
+use crate::filter::{Pattern, RepoFilter};
+use crate::git::layout::Layout;
+use crate::input::prompts::{
+    PROMPT_BB_PROJECT_ALL, PROMPT_BB_PROJECT_EXCLUDE, PROMPT_BB_REPO_EXCLUDE,
+    PROMPT_BB_REPO_FILTERS_STORED, PROMPT_BB_REPO_INCLUDE, PROMPT_BB_SERVER, PROMPT_BB_USERNAME,
+};
+use crate::input::sets::get_set;
+use crate::input::{
+    clear_stored, get_bool, get_password, get_stored_list, get_with_default, password_from_env,
+    store_list,
+};
+use crate::util::fail;
 use dialoguer::Confirm;
 
 #[derive(StructOpt, Debug, Clone)]
@@ -21,6 +32,10 @@ pub enum Opts {
     CloneProjects(CloneOpts),
     #[structopt(about = "Clone users")]
     CloneUsers(CloneOpts),
+    #[structopt(about = "List repos, without cloning")]
+    List(ListOpts),
+    #[structopt(about = "Manage named repo sets")]
+    Sets(SetsOpts),
     #[structopt(about = "Generate shell completions")]
     Completions,
 }
@@ -34,12 +49,91 @@ pub struct CloneOpts {
         help = "Run terminal in batch mode, with no interactions."
     )]
     pub batch_mode: bool,
+    #[structopt(
+        long = "resume",
+        help = "Continue the last run with the repos it did not get to, without fetching the repo list again."
+    )]
+    pub resume: bool,
+    #[structopt(
+        long = "retry-failed",
+        help = "Rerun only the repos that failed in the last run, without fetching the repo list again."
+    )]
+    pub retry_failed: bool,
+    #[structopt(
+        long = "fail-threshold",
+        default_value = "0",
+        help = "Failed repos to tolerate before exiting with an error, as a count or a percentage like '5%'."
+    )]
+    pub fail_threshold: FailThreshold,
     #[structopt(flatten)]
     pub bitbucket_opts: BitBucketOpts,
     #[structopt(flatten)]
     pub git_opts: GitOpts,
 }
 
+#[derive(StructOpt, Debug, Clone)]
+pub struct ListOpts {
+    #[structopt(
+        short = "B",
+        long = "batch",
+        name = "batch_mode",
+        help = "Run terminal in batch mode, with no interactions."
+    )]
+    pub batch_mode: bool,
+    #[structopt(
+        long = "show-labels",
+        name = "show_labels",
+        help = "Fetch and show the labels of every listed repo."
+    )]
+    pub show_labels: bool,
+    #[structopt(flatten)]
+    pub bitbucket_opts: BitBucketOpts,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum SetsOpts {
+    #[structopt(about = "List saved repo sets")]
+    List,
+    #[structopt(about = "Save a repo set, replacing any set with the same name")]
+    Save(SaveSetOpts),
+    #[structopt(about = "Delete a repo set")]
+    Delete {
+        #[structopt(name = "set_name")]
+        name: String,
+    },
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct SaveSetOpts {
+    #[structopt(name = "set_name")]
+    pub name: String,
+    #[structopt(
+        short = "k",
+        long = "key",
+        name = "set_project_keys",
+        help = "BitBucket Project keys (applicable multiple times)"
+    )]
+    pub project_keys: Vec<String>,
+    #[structopt(
+        long = "include",
+        name = "set_include",
+        help = "Repo patterns, same format as clone --include (applicable multiple times)"
+    )]
+    pub include: Vec<String>,
+    #[structopt(
+        long = "repo",
+        name = "set_repos",
+        help = "Single repos as 'project/slug' (applicable multiple times)"
+    )]
+    pub repos: Vec<String>,
+    #[structopt(
+        long = "user",
+        name = "set_users",
+        help = "Users whose personal repos are included (applicable multiple times)"
+    )]
+    pub users: Vec<String>,
+}
+
 #[derive(StructOpt, Clone, Debug)]
 pub struct BitBucketOpts {
     #[structopt(
@@ -93,6 +187,32 @@ pub struct BitBucketOpts {
         default_value = "ssh"
     )]
     pub clone_type: CloneType,
+    #[structopt(
+        long = "push-clone-type",
+        name = "push_clone_type",
+        help = "Set a separate push url of this clone type on origin.",
+        possible_values = & CloneType::variants(),
+        case_insensitive = true
+    )]
+    pub push_clone_type: Option<CloneType>,
+    #[structopt(
+        long = "archived",
+        name = "archived",
+        help = "What to do with archived repos.",
+        possible_values = & ArchivedPolicy::variants(),
+        case_insensitive = true,
+        default_value = "skip"
+    )]
+    pub archived: ArchivedPolicy,
+    #[structopt(
+        long = "inactive-users",
+        name = "inactive_users",
+        help = "What to do with the personal repos of deactivated users.",
+        possible_values = & InactiveUsersPolicy::variants(),
+        case_insensitive = true,
+        default_value = "skip"
+    )]
+    pub inactive_users: InactiveUsersPolicy,
     #[structopt(
         short = "k",
         long = "key",
@@ -107,6 +227,42 @@ pub struct BitBucketOpts {
         help = "Clone all projects"
     )]
     pub all: bool,
+    #[structopt(
+        long = "set",
+        name = "repo_set",
+        help = "Clone the repos of a saved repo set, see the sets command"
+    )]
+    pub set: Option<String>,
+    #[structopt(
+        long = "include",
+        name = "repo_include",
+        help = "Only repos matching pattern (applicable multiple times).\nGlob by default, 'regex:' prefix for a regular expression.\nPatterns with a '/' match 'project/slug', others only the slug. ie. 'PLAT/*-service'"
+    )]
+    pub include: Vec<String>,
+    #[structopt(
+        long = "exclude",
+        name = "repo_exclude",
+        help = "Skip repos matching pattern, same format as --include (applicable multiple times)"
+    )]
+    pub exclude: Vec<String>,
+    #[structopt(
+        long = "exclude-project",
+        name = "project_exclude",
+        help = "Skip projects with key matching pattern, ie. '~*' for all user repos (applicable multiple times)"
+    )]
+    pub exclude_project: Vec<String>,
+    #[structopt(
+        long = "label",
+        name = "repo_labels",
+        help = "Only repos with BitBucket repository label (applicable multiple times)"
+    )]
+    pub labels: Vec<String>,
+    #[structopt(
+        long = "exclude-label",
+        name = "repo_exclude_labels",
+        help = "Skip repos with BitBucket repository label (applicable multiple times)"
+    )]
+    pub exclude_labels: Vec<String>,
     #[structopt(
         long = "http-timeout",
         help = "HTTP timout, seconds.",
@@ -141,6 +297,18 @@ pub struct GitOpts {
         and switch to main branch"
     )]
     pub reset_state: bool,
+    #[structopt(
+        long = "reset-skip-unpushed",
+        requires = "git_reset_state",
+        help = "Don't reset repos with uncommitted changes or unpushed commits, list them instead."
+    )]
+    pub reset_skip_unpushed: bool,
+    #[structopt(
+        long = "reset-clean",
+        requires = "git_reset_state",
+        help = "Also remove untracked and ignored files when resetting, with git clean -fdx."
+    )]
+    pub reset_clean: bool,
     #[structopt(
         short = "g",
         long = "concurrent-git",
@@ -162,12 +330,203 @@ pub struct GitOpts {
         default_value = "."
     )]
     pub output_directory: String,
+    #[structopt(
+        long = "layout",
+        help = "Directory layout of clones below the output directory.\nPlaceholders: {project}, {project_name}, {repo}, {user}\nie. '{project_name}/{repo}' or flat '{project}-{repo}'",
+        default_value = "{project}/{repo}"
+    )]
+    pub layout: String,
+    #[structopt(
+        long = "user-layout",
+        help = "Directory layout of personal repos, defaults to --layout. ie. 'users/{user}/{repo}'"
+    )]
+    pub user_layout: Option<String>,
+    #[structopt(
+        long = "keep-case",
+        help = "Keep the original case of project keys, project names and repo slugs in directory names."
+    )]
+    pub keep_case: bool,
+    #[structopt(
+        long = "reclone-broken",
+        help = "Re-clone directories that are not clones of their repo, moving the old directory aside."
+    )]
+    pub reclone_broken: bool,
+    #[structopt(
+        long = "keep-remotes",
+        help = "Leave the origin urls of existing clones as they are."
+    )]
+    pub keep_remotes: bool,
+    #[structopt(
+        long = "always-fetch",
+        help = "Fetch every repo, instead of skipping those where ls-remote shows nothing new."
+    )]
+    pub always_fetch: bool,
+    #[structopt(
+        long = "clean-merged",
+        help = "Delete local branches that are merged into the main branch, after updating."
+    )]
+    pub clean_merged: bool,
+    #[structopt(
+        long = "update-all-branches",
+        help = "Fast-forward every local branch that tracks an upstream, not only the main branch."
+    )]
+    pub update_all_branches: bool,
+    #[structopt(
+        long = "rename-default-branch",
+        help = "When the default branch changes on the server, ie. master to main, rename the local branch too."
+    )]
+    pub rename_default_branch: bool,
+    #[structopt(
+        long = "depth",
+        help = "Shallow clone with this many commits of history."
+    )]
+    pub depth: Option<u32>,
+    #[structopt(
+        long = "filter",
+        possible_values = &["blob:none", "tree:0"],
+        help = "Partial clone, fetching blobs or trees only when needed."
+    )]
+    pub filter: Option<String>,
+    #[structopt(long = "single-branch", help = "Clone and fetch only the main branch.")]
+    pub single_branch: bool,
+    #[structopt(
+        long = "sparse",
+        help = "Check out only these directories, with sparse-checkout. Can be repeated."
+    )]
+    pub sparse: Vec<String>,
+    #[structopt(
+        long = "partial-repo",
+        help = "Use --depth, --filter, --single-branch and --sparse only for repos matching this pattern. \
+        Settings of its own can follow, like 'MONO/*;depth=1;filter=blob:none;single-branch;sparse=src', \
+        and then replace the global ones. Can be repeated, the first match wins, all repos when not given."
+    )]
+    pub partial_repos: Vec<PartialRepo>,
+    #[structopt(
+        long = "lfs",
+        help = "Fetch and check out Git LFS files in repos that use LFS."
+    )]
+    pub lfs: bool,
+    #[structopt(
+        long = "lfs-transfers",
+        default_value = "8",
+        help = "Concurrent LFS transfers per repo."
+    )]
+    pub lfs_transfers: usize,
+    #[structopt(
+        long = "submodules",
+        help = "Initialize and update submodules, recursively, in repos that have them."
+    )]
+    pub submodules: bool,
+    #[structopt(
+        long = "submodule-jobs",
+        default_value = "4",
+        help = "Submodules fetched in parallel per repo."
+    )]
+    pub submodule_jobs: usize,
+    #[structopt(
+        long = "reference-cache",
+        help = "Bare repo shared by new clones through git alternates, updated during the run. \
+        Clones depend on it unless --dissociate is given, so don't remove it."
+    )]
+    pub reference_cache: Option<String>,
+    #[structopt(
+        long = "dissociate",
+        requires = "reference-cache",
+        help = "Copy the objects borrowed from --reference-cache into each clone."
+    )]
+    pub dissociate: bool,
+    #[structopt(
+        long = "git-timeout",
+        help = "Seconds a single git command may run before it is killed."
+    )]
+    pub git_timeout: Option<u64>,
+    #[structopt(
+        long = "git-retries",
+        default_value = "2",
+        help = "Retries of git commands failing on network trouble, like early EOF or connection reset."
+    )]
+    pub git_retries: u32,
+    #[structopt(
+        long = "git-backoff",
+        default_value = "5",
+        help = "Linear backoff between git retries, expressed in seconds."
+    )]
+    pub git_backoff_sec: u64,
+    #[structopt(
+        long = "grace-period",
+        default_value = "30",
+        help = "Seconds running git commands get to finish after Ctrl-C, before they are killed."
+    )]
+    pub grace_period_sec: u64,
+    #[structopt(
+        long = "report",
+        help = "Write what happened to each repo to this file, as JUnit XML if it ends with .xml and JSON otherwise."
+    )]
+    pub report: Option<String>,
+    #[structopt(
+        long = "dry-run",
+        conflicts_with = "report",
+        help = "Print what would be cloned, updated, reset or cleaned up, without changing anything."
+    )]
+    pub dry_run: bool,
+    #[structopt(
+        long = "protect-branch",
+        help = "Never clean up branches matching this pattern, ie. 'release/*'. Can be repeated."
+    )]
+    pub protect_branches: Vec<String>,
+    #[structopt(
+        long = "clean-min-age-days",
+        default_value = "7",
+        help = "Only clean up branches created at least this many days ago."
+    )]
+    pub clean_min_age_days: u64,
     #[structopt(
         long = "git-ssl-allow-anything",
         help = "Allow self signed or invalid certificates for git"
     )]
     pub ssl_allow_anything: bool,
 }
+
+/// Git options as parsed from an empty command line, running one git at a time without retries.
+#[cfg(test)]
+pub fn test_opts(output_directory: &str) -> GitOpts {
+    GitOpts {
+        output_directory: output_directory.to_owned(),
+        concurrency: 1,
+        git_retries: 0,
+        git_backoff_sec: 0,
+        ..GitOpts::from_iter(&["test"])
+    }
+}
+
+/// BitBucket options as parsed from an empty command line, over http, one request at a time
+/// and without retries.
+#[cfg(test)]
+pub fn test_bitbucket_opts() -> BitBucketOpts {
+    BitBucketOpts {
+        concurrency: 1,
+        clone_type: CloneType::HTTP,
+        timeout_sec: 5,
+        retries: 0,
+        ..BitBucketOpts::from_iter(&["test"])
+    }
+}
+
+/// A repo as listed by BitBucket, with lowercased key and slug and no urls.
+#[cfg(test)]
+pub fn test_repo(project_key: &str, name: &str) -> Repo {
+    Repo {
+        project_key: project_key.to_lowercase(),
+        project_name: String::new(),
+        original_project_key: project_key.to_owned(),
+        git: String::new(),
+        name: name.to_lowercase(),
+        original_name: name.to_owned(),
+        labels: vec![],
+        push_url: None,
+        default_branch: None,
+    }
+}
 arg_enum! {
     #[allow(clippy::upper_case_acronyms)]
     #[derive(Clone, Debug)]
@@ -177,60 +536,220 @@ arg_enum! {
         HttpSavedLogin,
     }
 }
+arg_enum! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum ArchivedPolicy {
+        Skip,
+        Include,
+        Only,
+    }
+}
+arg_enum! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum InactiveUsersPolicy {
+        Skip,
+        Include,
+    }
+}
+
+/// Process exit codes, so that cron and CI can tell what went wrong.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exit {
+    Success = 0,
+    Config = 1,
+    GitFailures = 2,
+    Inventory = 3,
+    Auth = 4,
+    Io = 5,
+    Interrupted = 130,
+}
+
+impl Exit {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    pub fn from_error(e: &Error) -> Exit {
+        match e.kind {
+            ErrorKind::Auth(_) => Exit::Auth,
+            ErrorKind::Http(_)
+            | ErrorKind::Tls
+            | ErrorKind::Network
+            | ErrorKind::Timeout
+            | ErrorKind::Decode => Exit::Inventory,
+            ErrorKind::Git(_) => Exit::GitFailures,
+            ErrorKind::Cancelled => Exit::Interrupted,
+            ErrorKind::Io => Exit::Io,
+            ErrorKind::Config => Exit::Config,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailThreshold {
+    Count(usize),
+    Percent(f64),
+}
+
+impl FailThreshold {
+    pub fn exceeded(&self, failed: usize, total: usize) -> bool {
+        match *self {
+            FailThreshold::Count(count) => failed > count,
+            FailThreshold::Percent(percent) => failed as f64 * 100.0 > percent * total as f64,
+        }
+    }
+}
+
+impl FromStr for FailThreshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let bad = || {
+            format!(
+                "Bad fail threshold '{}', use a count or a percentage like 5%",
+                s
+            )
+        };
+        match s.trim().strip_suffix('%') {
+            Some(percent) => match percent.trim().parse::<f64>() {
+                Ok(p) if (0.0..=100.0).contains(&p) => Ok(FailThreshold::Percent(p)),
+                _ => Err(bad()),
+            },
+            None => s
+                .trim()
+                .parse()
+                .map(FailThreshold::Count)
+                .map_err(|_| bad()),
+        }
+    }
+}
+
+/// Shallow, partial and sparse clone settings.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PartialSettings {
+    pub depth: Option<u32>,
+    pub filter: Option<String>,
+    pub single_branch: bool,
+    pub sparse: Vec<String>,
+}
+
+/// A --partial-repo pattern, with settings of its own or else the global ones.
+#[derive(Clone, Debug)]
+pub struct PartialRepo {
+    pub pattern: Pattern,
+    pub settings: Option<PartialSettings>,
+}
+
+impl FromStr for PartialRepo {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = s.split(';');
+        let pattern = Pattern::parse(parts.next().unwrap_or_default()).map_err(|e| e.msg)?;
+        let mut settings: Option<PartialSettings> = None;
+        for part in parts.map(str::trim).filter(|p| !p.is_empty()) {
+            let current = settings.get_or_insert_with(PartialSettings::default);
+            match part.split_once('=') {
+                Some(("depth", depth)) => {
+                    current.depth = Some(
+                        depth
+                            .parse()
+                            .map_err(|_| format!("Bad depth '{}' in '{}'", depth, s))?,
+                    )
+                }
+                Some(("filter", filter)) if ["blob:none", "tree:0"].contains(&filter) => {
+                    current.filter = Some(filter.to_owned())
+                }
+                Some(("sparse", dir)) => current.sparse.push(dir.to_owned()),
+                None if part == "single-branch" => current.single_branch = true,
+                _ => {
+                    return Err(format!(
+                        "Bad setting '{}' in '{}', use depth=N, filter=blob:none, filter=tree:0, \
+                         single-branch or sparse=DIR",
+                        part, s
+                    ))
+                }
+            }
+        }
+        Ok(PartialRepo { pattern, settings })
+    }
+}
+
+impl GitOpts {
+    /// The partial clone settings for the repo, or None when it is cloned in full.
+    pub fn partial_settings(&self, repo: &Repo) -> Option<PartialSettings> {
+        let global = || PartialSettings {
+            depth: self.depth,
+            filter: self.filter.clone(),
+            single_branch: self.single_branch,
+            sparse: self.sparse.clone(),
+        };
+        if self.partial_repos.is_empty() {
+            return Some(global());
+        }
+        self.partial_repos
+            .iter()
+            .find(|p| p.pattern.matches_repo(repo))
+            .map(|p| p.settings.clone().unwrap_or_else(global))
+    }
+}
 
 impl CloneOpts {
     pub fn validate(&mut self) -> Result<()> {
-        if self.interactive() {
-            self.bitbucket_opts.server = match self.bitbucket_opts.server.clone() {
-                None => get_with_default(&PROMPT_BB_SERVER, None, false),
-                Some(s) => Some(s),
-            };
-            self.bitbucket_opts.username = match self.bitbucket_opts.username.clone() {
-                None => get_with_default(&PROMPT_BB_USERNAME, None, true),
-                Some(s) => Some(s),
-            };
-            self.bitbucket_opts.password = match self.bitbucket_opts.username {
-                None => None,
-                Some(_) if self.bitbucket_opts.password_from_env => None,
-                Some(_) if self.bitbucket_opts.password.is_none() => get_password(),
-                _ => None,
-            };
+        if self.interactive() && !self.resuming() {
+            self.bitbucket_opts.prompt_missing();
+            self.bitbucket_opts.use_stored_filters();
             self.bitbucket_opts.all = self.bitbucket_opts.all
-                || (self.bitbucket_opts.project_keys().is_empty()
+                || (!self.bitbucket_opts.has_selection()
                     && get_bool(&PROMPT_BB_PROJECT_ALL, false));
         }
         self.do_create_output_dir()?;
-        if self.bitbucket_opts.server.is_none() {
-            bail("Server is required")?;
-        } else if !self.bitbucket_opts.all
-            && self.bitbucket_opts.project_keys().is_empty()
-            && self.batch_mode
-        {
-            bail("project selection is required (all or keys)")?;
-        } else if self.git_opts.concurrency > 100 || self.bitbucket_opts.concurrency > 100 {
-            bail("Max concurrent actions = 100")?;
+        if !self.bitbucket_opts.has_selection() && self.batch_mode && !self.resuming() {
+            fail(
+                ErrorKind::Config,
+                "project selection is required (all, keys, include, label or set)",
+            )?;
+        } else if self.git_opts.concurrency > 100 {
+            fail(ErrorKind::Config, "Max concurrent actions = 100")?;
         } else if !Path::new(&self.git_opts.output_directory).exists() {
-            bail("output_directory is not accessible, does it exist?")?;
+            fail(
+                ErrorKind::Config,
+                "output_directory is not accessible, does it exist?",
+            )?;
         }
-        if self.bitbucket_opts.password_from_env {
-            match password_from_env() {
-                Ok(pass) => self.bitbucket_opts.password = Some(pass),
-                Err(e) => bail(&format!("Failed getting env password. {}", e.msg))?,
-            }
+        Layout::validate_template(&self.git_opts.layout)?;
+        if let Some(cache) = &self.git_opts.reference_cache {
+            // Clones run from the output directory, so the cache must not be relative.
+            let cache = std::env::current_dir()?.join(cache);
+            self.git_opts.reference_cache = Some(cache.to_string_lossy().into_owned());
         }
-        Ok(())
+        if let Some(user_layout) = &self.git_opts.user_layout {
+            Layout::validate_template(user_layout)?;
+        }
+        if self.resuming() {
+            // The repo list of the last run is used, the server is not asked again.
+            return self.bitbucket_opts.validate_login(self.interactive());
+        }
+        self.bitbucket_opts.validate()
+    }
+
+    pub fn resuming(&self) -> bool {
+        self.resume || self.retry_failed
     }
 
     fn do_create_output_dir(&self) -> Result<()> {
         if !Path::new(&self.git_opts.output_directory).exists() {
             if self.batch_mode {
-                bail(&format!(
-                    "Output directory {} doesn't exist",
-                    &self.git_opts.output_directory
-                ))?;
+                fail(
+                    ErrorKind::Config,
+                    &format!(
+                        "Output directory {} doesn't exist",
+                        &self.git_opts.output_directory
+                    ),
+                )?;
             }
             match Confirm::new()
-                .with_prompt(&format!(
+                .with_prompt(format!(
                     "Output dir {} does not exist, want me to create it?",
                     &self.git_opts.output_directory
                 ))
@@ -239,10 +758,13 @@ impl CloneOpts {
             {
                 Ok(true) => match std::fs::create_dir_all(&self.git_opts.output_directory) {
                     Ok(_) => {}
-                    _ => bail("Failed creating output directory.")?,
+                    _ => fail(ErrorKind::Io, "Failed creating output directory.")?,
                 },
-                Ok(false) => bail("Unable to proceed without an output directory")?,
-                Err(e) => bail(&format!("{:?}", e))?,
+                Ok(false) => fail(
+                    ErrorKind::Config,
+                    "Unable to proceed without an output directory",
+                )?,
+                Err(e) => fail(ErrorKind::Io, &format!("{:?}", e))?,
             }
         }
         Ok(())
@@ -253,6 +775,16 @@ impl CloneOpts {
     }
 }
 
+impl ListOpts {
+    pub fn validate(&mut self) -> Result<()> {
+        if !self.batch_mode {
+            self.bitbucket_opts.prompt_missing();
+            self.bitbucket_opts.use_stored_filters();
+        }
+        self.bitbucket_opts.validate()
+    }
+}
+
 impl BitBucketOpts {
     pub fn project_keys(&self) -> Vec<String> {
         self.project_keys
@@ -260,6 +792,137 @@ impl BitBucketOpts {
             .map(|key| key.to_lowercase())
             .collect()
     }
+
+    pub fn has_selection(&self) -> bool {
+        self.all
+            || !self.project_keys.is_empty()
+            || !self.include.is_empty()
+            || !self.labels.is_empty()
+            || self.set.is_some()
+    }
+
+    pub fn has_filters(&self) -> bool {
+        !self.include.is_empty() || !self.exclude.is_empty() || !self.exclude_project.is_empty()
+    }
+
+    pub fn filter_summary(&self) -> Vec<String> {
+        let mut summary = vec![];
+        for (name, patterns) in &[
+            ("include", &self.include),
+            ("exclude", &self.exclude),
+            ("exclude project", &self.exclude_project),
+            ("label", &self.labels),
+            ("exclude label", &self.exclude_labels),
+        ] {
+            if !patterns.is_empty() {
+                summary.push(format!("{}: {}", name, patterns.join(", ")));
+            }
+        }
+        summary
+    }
+
+    fn prompt_missing(&mut self) {
+        self.server = match self.server.clone() {
+            None => get_with_default(&PROMPT_BB_SERVER, None, false),
+            Some(s) => Some(s),
+        };
+        self.username = match self.username.clone() {
+            None => get_with_default(&PROMPT_BB_USERNAME, None, true),
+            Some(s) => Some(s),
+        };
+        self.password = match self.username {
+            None => None,
+            Some(_) if self.password_from_env => None,
+            Some(_) if self.password.is_none() => get_password(),
+            _ => None,
+        };
+    }
+
+    /// The filters are one unit: any filter given on the command line replaces all stored ones.
+    fn use_stored_filters(&mut self) {
+        if self.has_filters() {
+            store_list(&PROMPT_BB_REPO_INCLUDE, &self.include);
+            store_list(&PROMPT_BB_REPO_EXCLUDE, &self.exclude);
+            store_list(&PROMPT_BB_PROJECT_EXCLUDE, &self.exclude_project);
+            return;
+        }
+        self.include = get_stored_list(&PROMPT_BB_REPO_INCLUDE);
+        self.exclude = get_stored_list(&PROMPT_BB_REPO_EXCLUDE);
+        self.exclude_project = get_stored_list(&PROMPT_BB_PROJECT_EXCLUDE);
+        if self.has_filters() {
+            for line in self.filter_summary() {
+                println!("Stored filter {}", line);
+            }
+            if !get_bool(&PROMPT_BB_REPO_FILTERS_STORED, true) {
+                for prompt in &[
+                    PROMPT_BB_REPO_INCLUDE,
+                    PROMPT_BB_REPO_EXCLUDE,
+                    PROMPT_BB_PROJECT_EXCLUDE,
+                ] {
+                    clear_stored(prompt);
+                }
+                self.include.clear();
+                self.exclude.clear();
+                self.exclude_project.clear();
+            }
+        }
+    }
+
+    fn validate(&mut self) -> Result<()> {
+        if self.server.is_none() {
+            fail(ErrorKind::Config, "Server is required")?;
+        } else if self.concurrency > 100 {
+            fail(ErrorKind::Config, "Max concurrent actions = 100")?;
+        } else if self.set.is_some() && (self.all || !self.project_keys.is_empty()) {
+            fail(
+                ErrorKind::Config,
+                "A repo set can not be combined with all or keys",
+            )?;
+        }
+        RepoFilter::new(self)?;
+        if let Some(name) = &self.set {
+            get_set(name)?;
+        }
+        self.load_env_password()
+    }
+
+    /// The login that saved login urls are rebuilt with, when resuming without asking the server.
+    fn validate_login(&mut self, interactive: bool) -> Result<()> {
+        self.load_env_password()?;
+        let saves_login = |t: &CloneType| matches!(t, CloneType::HttpSavedLogin);
+        if !saves_login(&self.clone_type) && !self.push_clone_type.as_ref().is_some_and(saves_login)
+        {
+            return Ok(());
+        }
+        if interactive {
+            if self.username.is_none() {
+                self.username = get_with_default(&PROMPT_BB_USERNAME, None, true);
+            }
+            if self.password.is_none() {
+                self.password = get_password();
+            }
+        }
+        if self.username.is_none() || self.password.is_none() {
+            fail(
+                ErrorKind::Config,
+                "A username and password are required to resume clones with saved logins",
+            )?;
+        }
+        Ok(())
+    }
+
+    fn load_env_password(&mut self) -> Result<()> {
+        if self.password_from_env {
+            match password_from_env() {
+                Ok(pass) => self.password = Some(pass),
+                Err(e) => fail(
+                    ErrorKind::Config,
+                    &format!("Failed getting env password. {}", e.msg),
+                )?,
+            }
+        }
+        Ok(())
+    }
 }
 #[cfg(test)]
 mod test {
@@ -323,4 +986,81 @@ mod test {
         }
         std::env::remove_var("BITBUCKET_PASSWORD");
     }
+
+    #[test]
+    fn test_parsing_filters_without_all() {
+        let opt: Opts = Opts::from_iter(&[
+            "bitbucket_server_cli",
+            "clone",
+            "--server",
+            "https://bitbucket.example.com",
+            "--batch",
+            "--include",
+            "PLAT/*-service",
+            "--exclude",
+            "regex:legacy",
+            "--exclude-project",
+            "~*",
+        ]);
+        match opt {
+            Opts::Clone(mut co) => {
+                co.validate().unwrap();
+                assert_eq!(co.bitbucket_opts.include, vec!["PLAT/*-service"]);
+                assert_eq!(co.bitbucket_opts.exclude, vec!["regex:legacy"]);
+                assert_eq!(co.bitbucket_opts.exclude_project, vec!["~*"]);
+            }
+            _ => panic!("Bad format"),
+        }
+    }
+
+    #[test]
+    fn test_exit_from_error() {
+        let exit = |kind: ErrorKind| Exit::from_error(&Error::new(kind, "failed"));
+        assert_eq!(exit(ErrorKind::Auth(401)), Exit::Auth);
+        assert_eq!(exit(ErrorKind::Http(404)), Exit::Inventory);
+        assert_eq!(exit(ErrorKind::Tls), Exit::Inventory);
+        assert_eq!(exit(ErrorKind::Config), Exit::Config);
+        assert_eq!(exit(ErrorKind::Io), Exit::Io);
+    }
+
+    #[test]
+    fn test_fail_threshold() {
+        assert_eq!("3".parse(), Ok(FailThreshold::Count(3)));
+        assert_eq!("5%".parse(), Ok(FailThreshold::Percent(5.0)));
+        assert!("-1".parse::<FailThreshold>().is_err());
+        assert!("150%".parse::<FailThreshold>().is_err());
+        assert!(!FailThreshold::Count(0).exceeded(0, 10));
+        assert!(FailThreshold::Count(0).exceeded(1, 10));
+        assert!(!FailThreshold::Percent(5.0).exceeded(5, 100));
+        assert!(FailThreshold::Percent(5.0).exceeded(6, 100));
+    }
+
+    #[test]
+    fn test_dry_run_conflicts_with_report() {
+        let args = ["test", "--dry-run", "--report", "report.json"];
+        assert!(GitOpts::from_iter_safe(&args).is_err());
+        assert!(GitOpts::from_iter_safe(&args[..2]).is_ok());
+    }
+
+    #[test]
+    fn test_partial_repo() {
+        let mono: PartialRepo =
+            "MONO/*;depth=1;filter=blob:none;single-branch;sparse=src;sparse=docs"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            mono.settings,
+            Some(PartialSettings {
+                depth: Some(1),
+                filter: Some("blob:none".to_owned()),
+                single_branch: true,
+                sparse: vec!["src".to_owned(), "docs".to_owned()],
+            })
+        );
+        assert!("WEB/*".parse::<PartialRepo>().unwrap().settings.is_none());
+        assert!("MONO/*;depth=x".parse::<PartialRepo>().is_err());
+        assert!("MONO/*;filter=sparse".parse::<PartialRepo>().is_err());
+        assert!("MONO/*;shallow".parse::<PartialRepo>().is_err());
+        assert!("regex:[".parse::<PartialRepo>().is_err());
+    }
 }
@@ -1,23 +1,247 @@
 use std::path::Path;
-use std::process::Output;
+use std::process::{Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use generic_error::{GenericError, Result};
 use tokio::process::Command as TokioCommand;
+use tokio::sync::Notify;
 
-pub fn bail<T>(msg: &str) -> Result<T> {
-    Err(GenericError {
-        msg: msg.to_owned(),
-    })
+use crate::error::{Error, ErrorKind, Result};
+
+pub fn fail<T>(kind: ErrorKind, msg: &str) -> Result<T> {
+    Err(Error::new(kind, msg))
+}
+
+#[cfg(target_os = "windows")]
+const SHELL: (&str, &str) = ("cmd", "/C");
+#[cfg(not(target_os = "windows"))]
+const SHELL: (&str, &str) = ("sh", "-c");
+
+static IS_CANCELLED: AtomicBool = AtomicBool::new(false);
+static CANCEL: Notify = Notify::const_new();
+/// Commands run in their own process group, out of reach of the terminal's Ctrl-C,
+/// so they are kept track of here to be killed when quitting.
+static RUNNING: Mutex<Vec<u32>> = Mutex::new(vec![]);
+static HANDLING_INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+/// Kill all running commands, and refuse to start new ones.
+pub fn cancel() {
+    IS_CANCELLED.store(true, Ordering::SeqCst);
+    CANCEL.notify_waiters();
+}
+
+async fn cancelled() {
+    let notified = CANCEL.notified();
+    if !IS_CANCELLED.load(Ordering::SeqCst) {
+        notified.await;
+    }
+}
+
+/// Kill the running commands and everything they started, before quitting.
+pub async fn kill_running() {
+    let pids: Vec<u32> = RUNNING
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone();
+    for pid in pids {
+        kill_tree(pid).await;
+    }
+}
+
+/// Whether a run is taking care of interrupts itself, see quit_on_interrupt.
+pub fn handle_interrupts(handling: bool) {
+    HANDLING_INTERRUPTS.store(handling, Ordering::SeqCst);
+}
+
+/// Quit with the exit code on Ctrl-C, unless a run is taking care of it.
+pub async fn quit_on_interrupt(code: i32) {
+    loop {
+        interrupted().await;
+        if !HANDLING_INTERRUPTS.load(Ordering::SeqCst) {
+            kill_running().await;
+            std::process::exit(code);
+        }
+    }
+}
+
+struct Running(u32);
+
+impl Running {
+    fn new(pid: u32) -> Running {
+        RUNNING
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(pid);
+        Running(pid)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .retain(|pid| *pid != self.0);
+    }
+}
+
+/// Resolves on Ctrl-C, or SIGTERM where there is such a thing.
+pub async fn interrupted() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
 
 pub async fn exec<P: AsRef<Path>>(cmd: &str, path: P) -> Result<Output> {
+    exec_timeout(cmd, path, None).await
+}
+
+/// Run a command where git can't prompt for passwords or host keys,
+/// killing it and everything it started if it runs longer than the timeout.
+pub async fn exec_timeout<P: AsRef<Path>>(
+    cmd: &str,
+    path: P,
+    timeout: Option<Duration>,
+) -> Result<Output> {
+    if IS_CANCELLED.load(Ordering::SeqCst) {
+        return fail(ErrorKind::Cancelled, "cancelled");
+    }
+    let (shell, first) = SHELL;
+    let mut command = TokioCommand::new(shell);
+    command
+        .args([first, cmd])
+        .current_dir(path)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if std::env::var_os("GIT_SSH_COMMAND").is_none() && std::env::var_os("GIT_SSH").is_none() {
+        command.env("GIT_SSH_COMMAND", "ssh -o BatchMode=yes");
+    }
+    #[cfg(unix)]
+    command.process_group(0);
+    let child = command.spawn()?;
+    let pid = child.id();
+    let _running = pid.map(Running::new);
+    let output = child.wait_with_output();
+    tokio::pin!(output);
+    let expired = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => futures::future::pending().await,
+        }
+    };
+    tokio::select! {
+        output = &mut output => Ok(output?),
+        _ = expired => {
+            if let Some(pid) = pid {
+                kill_tree(pid).await;
+            }
+            let secs = timeout.map(|t| t.as_secs()).unwrap_or(0);
+            fail(ErrorKind::Timeout, &format!("timed out after {}s", secs))
+        }
+        _ = cancelled() => {
+            if let Some(pid) = pid {
+                kill_tree(pid).await;
+            }
+            fail(ErrorKind::Cancelled, "cancelled")
+        }
+    }
+}
+
+/// Kill a process and its children, while it is still around to find them by.
+async fn kill_tree(pid: u32) {
     #[cfg(target_os = "windows")]
-    let (shell, first) = ("cmd", "/C");
+    let cmd = format!("taskkill /T /F /PID {}", pid);
+    // The process leads its own process group, see process_group(0) above.
     #[cfg(not(target_os = "windows"))]
-    let (shell, first) = ("sh", "-c");
-    Ok(TokioCommand::new(shell)
-        .args(&[first, cmd])
-        .current_dir(path)
-        .output()
-        .await?)
+    let cmd = format!("kill -s KILL -- -{}", pid);
+    let (shell, first) = SHELL;
+    if let Err(e) = TokioCommand::new(shell).args([first, &cmd]).output().await {
+        eprintln!("Failed killing process {}. {:?}", pid, e);
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// UTC time formatted like '20210314-152653', safe to use in branch and file names.
+pub fn timestamp(unix_secs: u64) -> String {
+    let days = (unix_secs / 86400) as i64;
+    let secs = unix_secs % 86400;
+    // Civil date from days since epoch, after Howard Hinnant's days_from_civil inverse.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_running_is_tracked_until_dropped() {
+        let pid = u32::MAX - 7;
+        let running = || RUNNING.lock().unwrap().contains(&pid);
+        let guard = Running::new(pid);
+        assert!(running());
+        drop(guard);
+        assert!(!running());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_exec_timeout() {
+        let started = std::time::Instant::now();
+        let result =
+            exec_timeout("sleep 30 & sleep 30", "/tmp", Some(Duration::from_secs(1))).await;
+        assert_eq!(result.unwrap_err().kind, ErrorKind::Timeout);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let quick = exec_timeout("echo done", "/tmp", Some(Duration::from_secs(10))).await;
+        assert!(quick.unwrap().status.success());
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(0), "19700101-000000");
+        assert_eq!(timestamp(1_615_735_613), "20210314-152653");
+        assert_eq!(timestamp(951_782_400), "20000229-000000");
+    }
 }